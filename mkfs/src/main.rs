@@ -0,0 +1,260 @@
+// Build a file system image for rxv6.
+//
+//   mkfs fs.img files...
+//
+// The image holds an empty log, an inode for the root directory,
+// and one regular file in the root directory for each argument.
+// A leading '_' is stripped from file names, so user programs
+// built as _name show up as /name.
+
+use std::cmp::min;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::path::Path;
+use std::process;
+
+use self::layout::{
+    as_bytes, as_bytes_mut, iblock, Dinode, Dirent, SuperBlock, BPB, BSIZE, DIRSIZ, FSMAGIC, IPB,
+    MAXFILE, NDIRECT, NINDIRECT, ROOTINO, T_DIR, T_FILE,
+};
+
+#[allow(dead_code)]
+#[path = "../../src/fs/layout.rs"]
+mod layout;
+
+// the kernel reads the image in its own (little-endian) byte order.
+#[cfg(target_endian = "big")]
+compile_error!("mkfs must run on a little-endian host");
+
+const NINODES: u32 = 200;
+const FSSIZE: u32 = 2000; // size of file system in blocks
+const LOGSIZE: u32 = 30; // keep in sync with LOGSIZE in the kernel's arch.rs
+
+// Disk layout:
+// [ boot block | sb block | log | inode blocks | free bit map | data blocks ]
+
+const NBITMAP: u32 = FSSIZE / BPB + 1;
+const NINODEBLOCKS: u32 = NINODES / IPB + 1;
+const NLOG: u32 = LOGSIZE;
+
+// Number of meta blocks (boot, sb, nlog, inode, bitmap)
+const NMETA: u32 = 2 + NLOG + NINODEBLOCKS + NBITMAP;
+// Number of data blocks
+const NBLOCKS: u32 = FSSIZE - NMETA;
+
+struct Mkfs {
+    fsfd: File,
+    sb: SuperBlock,
+    freeinode: u32,
+    freeblock: u32, // the first free block that we can allocate
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: mkfs fs.img files...");
+        process::exit(1);
+    }
+
+    if let Err(err) = mkfs(&args[1], &args[2..]) {
+        eprintln!("mkfs: {}", err);
+        process::exit(1);
+    }
+}
+
+fn mkfs(img: &str, files: &[String]) -> io::Result<()> {
+    assert_eq!(BSIZE % size_of::<Dinode>(), 0);
+    assert_eq!(BSIZE % size_of::<Dirent>(), 0);
+
+    let fsfd = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(img)?;
+
+    // 1 fs block = 1 disk sector
+    let sb = SuperBlock {
+        magic: FSMAGIC,
+        size: FSSIZE,
+        nblocks: NBLOCKS,
+        ninodes: NINODES,
+        nlog: NLOG,
+        logstart: 2,
+        inodestart: 2 + NLOG,
+        bmapstart: 2 + NLOG + NINODEBLOCKS,
+    };
+
+    println!(
+        "nmeta {} (boot, super, log blocks {} inode blocks {}, bitmap blocks {}) blocks {} total {}",
+        NMETA, NLOG, NINODEBLOCKS, NBITMAP, NBLOCKS, FSSIZE
+    );
+
+    let mut fs = Mkfs {
+        fsfd,
+        sb,
+        freeinode: 1,
+        freeblock: NMETA,
+    };
+
+    let zeroes = [0; BSIZE];
+    for i in 0..FSSIZE {
+        fs.wsect(i, &zeroes)?;
+    }
+
+    let mut buf = [0; BSIZE];
+    buf[..size_of::<SuperBlock>()].copy_from_slice(as_bytes(&sb));
+    fs.wsect(1, &buf)?;
+
+    let rootino = fs.ialloc(T_DIR)?;
+    assert_eq!(rootino, ROOTINO);
+
+    fs.dirlink(rootino, b".", rootino)?;
+    fs.dirlink(rootino, b"..", rootino)?;
+
+    for file in files {
+        let path = Path::new(file);
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_else(|| panic!("bad file name {}", file));
+
+        // Skip leading _ in name when writing to file system.
+        // The binaries are named _rm, _cat, etc. to keep the
+        // build operating system from trying to execute them
+        // in place of system binaries like rm and cat.
+        let name = name.strip_prefix('_').unwrap_or(name);
+        assert!(name.len() <= DIRSIZ, "{}: name too long", name);
+
+        let data = fs::read(path)?;
+        let inum = fs.ialloc(T_FILE)?;
+        fs.dirlink(rootino, name.as_bytes(), inum)?;
+        fs.iappend(inum, &data)?;
+    }
+
+    // fix size of root inode dir
+    let mut din = fs.rinode(rootino)?;
+    din.size = (din.size / BSIZE as u32 + 1) * BSIZE as u32;
+    fs.winode(rootino, &din)?;
+
+    let used = fs.freeblock;
+    fs.balloc(used)
+}
+
+impl Mkfs {
+    fn wsect(&mut self, sec: u32, buf: &[u8; BSIZE]) -> io::Result<()> {
+        self.fsfd.seek(SeekFrom::Start(sec as u64 * BSIZE as u64))?;
+        self.fsfd.write_all(buf)
+    }
+
+    fn rsect(&mut self, sec: u32, buf: &mut [u8; BSIZE]) -> io::Result<()> {
+        self.fsfd.seek(SeekFrom::Start(sec as u64 * BSIZE as u64))?;
+        self.fsfd.read_exact(buf)
+    }
+
+    fn winode(&mut self, inum: u32, ip: &Dinode) -> io::Result<()> {
+        let bn = iblock(inum, &self.sb);
+        let off = (inum % IPB) as usize * size_of::<Dinode>();
+        let mut buf = [0; BSIZE];
+        self.rsect(bn, &mut buf)?;
+        buf[off..off + size_of::<Dinode>()].copy_from_slice(as_bytes(ip));
+        self.wsect(bn, &buf)
+    }
+
+    fn rinode(&mut self, inum: u32) -> io::Result<Dinode> {
+        let bn = iblock(inum, &self.sb);
+        let off = (inum % IPB) as usize * size_of::<Dinode>();
+        let mut buf = [0; BSIZE];
+        self.rsect(bn, &mut buf)?;
+        let mut ip = Dinode::default();
+        as_bytes_mut(&mut ip).copy_from_slice(&buf[off..off + size_of::<Dinode>()]);
+        Ok(ip)
+    }
+
+    fn ialloc(&mut self, typ: i16) -> io::Result<u32> {
+        let inum = self.freeinode;
+        self.freeinode += 1;
+        assert!(inum < NINODES, "ialloc: out of inodes");
+
+        let din = Dinode {
+            typ,
+            nlink: 1,
+            size: 0,
+            ..Default::default()
+        };
+        self.winode(inum, &din)?;
+        Ok(inum)
+    }
+
+    // mark the first used blocks as allocated in the bitmap.
+    fn balloc(&mut self, used: u32) -> io::Result<()> {
+        println!("balloc: first {} blocks have been allocated", used);
+        assert!(used < BPB);
+        let mut buf = [0; BSIZE];
+        for i in 0..used as usize {
+            buf[i / 8] |= 1 << (i % 8);
+        }
+        println!("balloc: write bitmap block at sector {}", self.sb.bmapstart);
+        self.wsect(self.sb.bmapstart, &buf)
+    }
+
+    fn dirlink(&mut self, dir: u32, name: &[u8], inum: u32) -> io::Result<()> {
+        let mut de = Dirent {
+            inum: inum as u16,
+            ..Default::default()
+        };
+        de.set_name(name);
+        self.iappend(dir, as_bytes(&de))
+    }
+
+    // append data to the end of file inum, allocating blocks as needed.
+    fn iappend(&mut self, inum: u32, data: &[u8]) -> io::Result<()> {
+        let mut din = self.rinode(inum)?;
+        let mut off = din.size as usize;
+        let mut data = data;
+        while !data.is_empty() {
+            let fbn = off / BSIZE;
+            assert!(fbn < MAXFILE, "iappend: file too big");
+            let x = if fbn < NDIRECT {
+                if din.addrs[fbn] == 0 {
+                    din.addrs[fbn] = self.alloc_block();
+                }
+                din.addrs[fbn]
+            } else {
+                if din.addrs[NDIRECT] == 0 {
+                    din.addrs[NDIRECT] = self.alloc_block();
+                }
+                let mut indirect = [0; BSIZE];
+                self.rsect(din.addrs[NDIRECT], &mut indirect)?;
+                let i = (fbn - NDIRECT) * 4;
+                debug_assert!(fbn - NDIRECT < NINDIRECT);
+                let mut addr = u32::from_le_bytes(indirect[i..i + 4].try_into().unwrap());
+                if addr == 0 {
+                    addr = self.alloc_block();
+                    indirect[i..i + 4].copy_from_slice(&addr.to_le_bytes());
+                    self.wsect(din.addrs[NDIRECT], &indirect)?;
+                }
+                addr
+            };
+            let n1 = min(data.len(), (fbn + 1) * BSIZE - off);
+            let mut buf = [0; BSIZE];
+            self.rsect(x, &mut buf)?;
+            let start = off - fbn * BSIZE;
+            buf[start..start + n1].copy_from_slice(&data[..n1]);
+            self.wsect(x, &buf)?;
+            off += n1;
+            data = &data[n1..];
+        }
+        din.size = off as u32;
+        self.winode(inum, &din)
+    }
+
+    fn alloc_block(&mut self) -> u32 {
+        let b = self.freeblock;
+        self.freeblock += 1;
+        assert!(b < FSSIZE, "mkfs: out of blocks");
+        b
+    }
+}
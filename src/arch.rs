@@ -11,6 +11,27 @@ pub(crate) const PGSIZE: u64 = 4096; // 4096 bytes;
 pub(crate) const NCPU: usize = 4; // number of cpus
 pub(crate) const INTERVAL: u64 = 1000000; // about 1/10th second in qemu.
 pub(crate) const NPROC: usize = 64; // maximum number of processes
+pub(crate) const MAXARG: usize = 32; // max exec arguments
+pub(crate) const MAXPATH: usize = 128; // maximum file path name
+pub(crate) const USERSTACK: u64 = 1; // user stack pages
+pub(crate) const MAXOPBLOCKS: usize = 10; // max # of blocks any FS op writes
+pub(crate) const LOGSIZE: usize = MAXOPBLOCKS * 3; // max data blocks in on-disk log
+pub(crate) const NBUF: usize = MAXOPBLOCKS * 3; // size of disk block cache
+pub(crate) const NOFILE: usize = 16; // open files per process
+pub(crate) const NFILE: usize = 100; // open files per system
+pub(crate) const NINODE: usize = 50; // maximum number of active i-nodes
+pub(crate) const NDEV: usize = 10; // maximum major device number
+pub(crate) const ROOTDEV: u32 = 1; // device number of file system root disk
+
+#[inline]
+pub(crate) const fn pg_round_up(addr: u64) -> u64 {
+    (addr + PGSIZE - 1) & !(PGSIZE - 1)
+}
+
+#[inline]
+pub(crate) const fn pg_round_down(addr: u64) -> u64 {
+    addr & !(PGSIZE - 1)
+}
 
 pub(crate) mod tp {
     #[inline]
@@ -135,6 +156,22 @@ pub(crate) fn make_satp(addr: u64) -> u64 {
     (addr >> 12) | (8 << 60)
 }
 
+#[inline]
+pub(crate) fn r_sstatus() -> usize {
+    let bits: usize;
+    unsafe {
+        asm!("csrr {}, sstatus", out(reg) bits);
+    }
+    bits
+}
+
+#[inline]
+pub(crate) fn w_sstatus(bits: usize) {
+    unsafe {
+        asm!("csrw sstatus, {}", in(reg) bits);
+    }
+}
+
 #[inline]
 pub(crate) fn w_sip(bits: usize) {
     unsafe {
@@ -0,0 +1,195 @@
+// Console input and output, to the uart.
+// Reads are line at a time.
+// Implements special input characters:
+//   newline -- end of line
+//   control-h -- backspace
+//   control-u -- kill line
+//   control-d -- end of file
+//   control-p -- print process list
+//   control-k -- print page allocator usage
+//   control-b -- print buffer cache hits and misses
+
+use crate::driver::uart::Uart;
+use crate::fs::bio;
+use crate::lock::spinlock::SpinLock;
+use crate::memory::kalloc::KALLOC;
+use crate::memory::vm::PageTable;
+use crate::process::master::PMASTER;
+use crate::syscall::Errno;
+use crate::{print, println};
+
+const INPUT_BUF_SIZE: usize = 128;
+
+// Control-x
+const fn ctrl(x: u8) -> u8 {
+    x - b'@'
+}
+
+const BACKSPACE: u8 = ctrl(b'H');
+const DELETE: u8 = 0x7f;
+
+pub(crate) struct Console {
+    buf: [u8; INPUT_BUF_SIZE],
+    r: usize, // Read index
+    w: usize, // Write index
+    e: usize, // Edit index
+}
+
+pub(crate) static CONS: SpinLock<Console> = SpinLock::new(Console::new());
+
+impl Console {
+    const fn new() -> Self {
+        Self {
+            buf: [0; INPUT_BUF_SIZE],
+            r: 0,
+            w: 0,
+            e: 0,
+        }
+    }
+}
+
+// the channel readers sleep on until a whole line has arrived.
+fn read_chan() -> usize {
+    &CONS as *const _ as usize
+}
+
+// send one character to the uart.
+// used to echo input characters, but not from write().
+fn putc(c: u8) {
+    let mut uart = Uart::new();
+    if c == BACKSPACE {
+        // if the user typed backspace, overwrite with a space.
+        uart.put(b'\x08');
+        uart.put(b' ');
+        uart.put(b'\x08');
+    } else {
+        uart.put(c);
+    }
+}
+
+// user write()s to the console go here.
+// copy n bytes from user address src and
+// return the number written.
+pub(crate) fn write(src: u64, n: usize) -> Result<usize, Errno> {
+    let pagetable = unsafe { PMASTER.my_proc().context.pagetable };
+    let mut uart = Uart::new();
+    for i in 0..n {
+        let mut c = [0];
+        if PageTable::copyin(pagetable, &mut c, src + i as u64).is_err() {
+            return Ok(i);
+        }
+        uart.write(c[0]);
+    }
+    Ok(n)
+}
+
+// user read()s from the console go here.
+// copy (up to) a whole input line to user address dst.
+// return the number of bytes copied.
+pub(crate) fn read(mut dst: u64, n: usize) -> Result<usize, Errno> {
+    let pagetable = unsafe { PMASTER.my_proc().context.pagetable };
+    let mut cons = CONS.lock();
+    let mut left = n;
+    while left > 0 {
+        // wait until interrupt handler has put some
+        // input into cons.buf.
+        while cons.r == cons.w {
+            if unsafe { PMASTER.killed() } {
+                return Err(Errno::EINTR);
+            }
+            cons = unsafe { PMASTER.sleep(read_chan(), cons) };
+        }
+
+        let c = cons.buf[cons.r % INPUT_BUF_SIZE];
+        cons.r += 1;
+
+        if c == ctrl(b'D') {
+            // end-of-file
+            if left < n {
+                // Save ^D for next time, to make sure
+                // caller gets a 0-byte result.
+                cons.r -= 1;
+            }
+            break;
+        }
+
+        // copy the input byte to the user-space buffer.
+        if PageTable::copyout(pagetable, dst, &[c]).is_err() {
+            break;
+        }
+
+        dst += 1;
+        left -= 1;
+
+        if c == b'\n' {
+            // a whole line has arrived, return to
+            // the user-level read().
+            break;
+        }
+    }
+    Ok(n - left)
+}
+
+// the console input interrupt handler.
+// uart intr() calls this for input character.
+// do erase/kill processing, append to cons.buf,
+// wake up read() if a whole line has arrived.
+pub(crate) fn intr(c: u8) {
+    let mut cons = CONS.lock();
+    let mut dump = None;
+
+    match c {
+        // Print process list, page allocator usage or buffer
+        // cache hits and misses, once cons is released below.
+        c if c == ctrl(b'P') || c == ctrl(b'K') || c == ctrl(b'B') => dump = Some(c),
+        // Kill line.
+        c if c == ctrl(b'U') => {
+            while cons.e != cons.w && cons.buf[(cons.e - 1) % INPUT_BUF_SIZE] != b'\n' {
+                cons.e -= 1;
+                putc(BACKSPACE);
+            }
+        }
+        // Backspace
+        BACKSPACE | DELETE => {
+            if cons.e != cons.w {
+                cons.e -= 1;
+                putc(BACKSPACE);
+            }
+        }
+        _ => {
+            if c != 0 && cons.e - cons.r < INPUT_BUF_SIZE {
+                let c = if c == b'\r' { b'\n' } else { c };
+
+                // echo back to the user.
+                putc(c);
+
+                // store for consumption by read().
+                let e = cons.e;
+                cons.buf[e % INPUT_BUF_SIZE] = c;
+                cons.e += 1;
+
+                if c == b'\n' || c == ctrl(b'D') || cons.e - cons.r == INPUT_BUF_SIZE {
+                    // wake up read() if a whole line (or end-of-file)
+                    // has arrived.
+                    cons.w = cons.e;
+                    unsafe {
+                        PMASTER.wakeup(read_chan());
+                    }
+                }
+            }
+        }
+    }
+    drop(cons);
+
+    // the dumps can be long; don't hold cons.lock while printing them.
+    match dump {
+        Some(c) if c == ctrl(b'P') => unsafe { PMASTER.procdump() },
+        Some(c) if c == ctrl(b'K') => KALLOC.dump(),
+        Some(c) if c == ctrl(b'B') => {
+            let (hits, misses) = bio::stats();
+            println!();
+            println!("bcache: {} hits, {} misses", hits, misses);
+        }
+        _ => {}
+    }
+}
@@ -1 +1,4 @@
+pub(crate) mod console;
 pub(crate) mod uart;
+pub(crate) mod virtio;
+pub(crate) mod virtio_disk;
@@ -4,8 +4,12 @@
 use core::convert::TryInto;
 use core::fmt::Error;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use crate::driver::console;
+use crate::lock::spinlock::{Guard, SpinLock};
 use crate::memory::layout::UART;
+use crate::process::master::PMASTER;
 
 /* This part of code is borrowed from
 https://github.com/sgmarz/osblog/blob/master/risc_v/src/uart.rs */
@@ -14,6 +18,71 @@ pub struct Uart {
     base_address: usize,
 }
 
+// the UART control registers, as offsets from base_address.
+const RHR: usize = 0; // receive holding register (for input bytes)
+const THR: usize = 0; // transmit holding register (for output bytes)
+const IER: usize = 1; // interrupt enable register
+const ISR: usize = 2; // interrupt status register
+const LSR: usize = 5; // line status register
+const LSR_RX_READY: u8 = 1 << 0; // input is waiting to be read from RHR
+const LSR_TX_IDLE: u8 = 1 << 5; // THR can accept another character to send
+
+const IER_RX_ENABLE: u8 = 1 << 0;
+const IER_TX_ENABLE: u8 = 1 << 1;
+
+const UART_TX_BUF_SIZE: usize = 32;
+
+// set by the panic handler; from then on output bypasses the
+// buffers so it still reaches the screen with locks held.
+pub static PANICKED: AtomicBool = AtomicBool::new(false);
+
+// a byte ring buffer. r and w only ever grow,
+// so the buffer is full when w == r + N.
+struct Ring<const N: usize> {
+    buf: [u8; N],
+    r: usize, // next byte to read
+    w: usize, // next byte to write
+}
+
+impl<const N: usize> Ring<N> {
+    const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            r: 0,
+            w: 0,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.w == self.r + N
+    }
+
+    fn push(&mut self, c: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.buf[self.w % N] = c;
+        self.w += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.r == self.w {
+            return None;
+        }
+        let c = self.buf[self.r % N];
+        self.r += 1;
+        Some(c)
+    }
+}
+
+// bytes waiting for the transmitter, filled by put() and write().
+static UART_TX: SpinLock<Ring<UART_TX_BUF_SIZE>> = SpinLock::new(Ring::new());
+
+fn tx_chan() -> usize {
+    &UART_TX as *const _ as usize
+}
+
 impl Write for Uart {
     fn write_str(&mut self, out: &str) -> Result<(), Error> {
         for c in out.bytes() {
@@ -50,8 +119,9 @@ impl Uart {
             ptr.add(2).write_volatile(1 << 0);
 
             // Enable receiver buffer interrupts, which is at bit index
-            // 0 of the interrupt enable register (IER at offset 1).
-            ptr.add(1).write_volatile(1 << 0);
+            // 0 of the interrupt enable register (IER at offset 1),
+            // and transmitter empty interrupts at bit index 1.
+            ptr.add(IER).write_volatile(IER_RX_ENABLE | IER_TX_ENABLE);
 
             // If we cared about the divisor, the code below would set the divisor
             // from a global clock rate of 22.729 MHz (22,729,000 cycles per second)
@@ -94,23 +164,112 @@ impl Uart {
         }
     }
 
+    // add a character to the output buffer and tell the
+    // UART to start sending if it isn't already.
+    // never sleeps, so print! can use it from any context;
+    // only if the buffer is full does it wait for the hardware.
     pub fn put(&mut self, c: u8) {
+        if PANICKED.load(Ordering::Relaxed) {
+            self.put_sync(c);
+            return;
+        }
+        let mut tx = UART_TX.lock();
+        while !tx.push(c) {
+            // buffer is full: hand one byte to the hardware ourselves.
+            self.wait_tx_idle();
+            self.start(&mut tx);
+        }
+        self.start(&mut tx);
+    }
+
+    // like put(), but sleeps while the output buffer is full
+    // instead of waiting on the hardware. for use by processes.
+    pub fn write(&mut self, c: u8) {
+        let mut tx = UART_TX.lock();
+        while tx.is_full() {
+            // wait for start() to open up space in the buffer.
+            tx = unsafe { PMASTER.sleep(tx_chan(), tx) };
+        }
+        tx.push(c);
+        self.start(&mut tx);
+    }
+
+    // send a character straight to the hardware,
+    // spinning until the transmitter is ready.
+    pub fn put_sync(&mut self, c: u8) {
+        self.wait_tx_idle();
         let ptr = self.base_address as *mut u8;
         unsafe {
-            ptr.add(0).write_volatile(c);
+            ptr.add(THR).write_volatile(c);
         }
     }
 
-    pub fn _get(&mut self) -> Option<u8> {
+    pub fn get(&mut self) -> Option<u8> {
         let ptr = self.base_address as *mut u8;
         unsafe {
-            if ptr.add(5).read_volatile() & 1 == 0 {
+            if ptr.add(LSR).read_volatile() & LSR_RX_READY == 0 {
                 // The DR bit is 0, meaning no data
                 None
             } else {
                 // The DR bit is 1, meaning data!
-                Some(ptr.add(0).read_volatile())
+                Some(ptr.add(RHR).read_volatile())
+            }
+        }
+    }
+
+    // handle a uart interrupt, raised because input has
+    // arrived, or the uart is ready for more output, or
+    // both. called from the trap handler.
+    pub fn intr(&mut self) {
+        // acknowledge the interrupt.
+        unsafe {
+            (self.base_address as *mut u8).add(ISR).read_volatile();
+        }
+
+        // read and process incoming characters.
+        while let Some(c) = self.get() {
+            console::intr(c);
+        }
+
+        // send buffered characters.
+        self.start(&mut UART_TX.lock());
+
+        // maybe write() is waiting for space in the buffer.
+        // the transmitter interrupts after every byte start()
+        // hands it, so waking writers here alone is enough.
+        unsafe {
+            PMASTER.wakeup(tx_chan());
+        }
+    }
+
+    // if the UART is idle, and a character is waiting
+    // in the transmit buffer, send it.
+    // caller must hold the UART_TX lock.
+    fn start(&mut self, tx: &mut Guard<Ring<UART_TX_BUF_SIZE>>) {
+        let ptr = self.base_address as *mut u8;
+        loop {
+            if unsafe { ptr.add(LSR).read_volatile() } & LSR_TX_IDLE == 0 {
+                // the UART transmit holding register is full,
+                // so we cannot give it another byte.
+                // it will interrupt when it's ready for a new byte.
+                return;
             }
+            let c = if let Some(c) = tx.pop() {
+                c
+            } else {
+                // transmit buffer is empty.
+                return;
+            };
+            unsafe {
+                ptr.add(THR).write_volatile(c);
+            }
+        }
+    }
+
+    fn wait_tx_idle(&self) {
+        let ptr = self.base_address as *mut u8;
+        while unsafe { ptr.add(LSR).read_volatile() } & LSR_TX_IDLE == 0 {
+            core::hint::spin_loop();
         }
     }
 }
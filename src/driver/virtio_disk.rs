@@ -0,0 +1,404 @@
+// driver for qemu's virtio disk device.
+// uses qemu's mmio interface to virtio.
+//
+// qemu ... -drive file=fs.img,if=none,format=raw,id=x0 -device virtio-blk-device,drive=x0,bus=virtio-mmio-bus.0
+
+use core::mem::size_of;
+use core::ptr::{self, read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+use super::virtio::*;
+use crate::arch::PGSIZE;
+use crate::lock::spinlock::SpinLock;
+use crate::memory::kalloc::KALLOC;
+use crate::memory::layout::VIRTIO0;
+use crate::process::master::PMASTER;
+
+// the size of a disk sector, the unit of the sector number in a request.
+pub(crate) const SECTOR_SIZE: usize = 512;
+
+#[derive(Clone, Copy)]
+struct Info {
+    done: bool, // set by intr() once the device has finished the request
+    status: u8, // written by the device
+}
+
+pub(crate) struct Disk {
+    // a set (not a ring) of DMA descriptors, with which the
+    // driver tells the device where to read and write individual
+    // disk operations. there are NUM descriptors.
+    // most commands consist of a "chain" (a linked list) of a couple of
+    // these descriptors.
+    desc: *mut VirtqDesc,
+
+    // a ring in which the driver writes descriptor numbers
+    // that the driver would like the device to process.  it only
+    // includes the head descriptor of each chain. the ring has
+    // NUM elements.
+    avail: *mut VirtqAvail,
+
+    // a ring in which the device writes descriptor numbers that
+    // the device has finished processing (just the head of each chain).
+    // there are NUM used ring entries.
+    used: *mut VirtqUsed,
+
+    // our own book-keeping.
+    free: [bool; NUM], // is a descriptor free?
+    used_idx: u16,     // we've looked this far in used[2..NUM].
+
+    // track info about in-flight operations,
+    // for use when completion interrupt arrives.
+    // indexed by first descriptor index of chain.
+    info: [Info; NUM],
+
+    // disk command headers.
+    // one-for-one with descriptors, for convenience.
+    ops: [VirtioBlkReq; NUM],
+}
+
+unsafe impl Send for Disk {}
+
+pub(crate) static DISK: SpinLock<Disk> = SpinLock::new(Disk::new());
+
+fn read_reg(offset: u64) -> u32 {
+    unsafe { read_volatile((VIRTIO0 + offset) as *const u32) }
+}
+
+fn write_reg(offset: u64, value: u32) {
+    unsafe { write_volatile((VIRTIO0 + offset) as *mut u32, value) }
+}
+
+impl Disk {
+    const fn new() -> Self {
+        Self {
+            desc: ptr::null_mut(),
+            avail: ptr::null_mut(),
+            used: ptr::null_mut(),
+            free: [false; NUM],
+            used_idx: 0,
+            info: [Info {
+                done: false,
+                status: 0,
+            }; NUM],
+            ops: [VirtioBlkReq {
+                typ: 0,
+                reserved: 0,
+                sector: 0,
+            }; NUM],
+        }
+    }
+
+    // find a free descriptor, mark it non-free, return its index.
+    fn alloc_desc(&mut self) -> Option<usize> {
+        let i = self.free.iter().position(|&free| free)?;
+        self.free[i] = false;
+        Some(i)
+    }
+
+    // mark a descriptor as free.
+    fn free_desc(&mut self, i: usize) {
+        assert!(i < NUM, "free_desc 1");
+        assert!(!self.free[i], "free_desc 2");
+        unsafe {
+            write_volatile(
+                self.desc.add(i),
+                VirtqDesc {
+                    addr: 0,
+                    len: 0,
+                    flags: 0,
+                    next: 0,
+                },
+            );
+        }
+        self.free[i] = true;
+        unsafe {
+            PMASTER.wakeup(free_chan());
+        }
+    }
+
+    // free a chain of descriptors.
+    fn free_chain(&mut self, mut i: usize) {
+        loop {
+            let desc = unsafe { read_volatile(self.desc.add(i)) };
+            self.free_desc(i);
+            if desc.flags & VRING_DESC_F_NEXT == 0 {
+                break;
+            }
+            i = desc.next as usize;
+        }
+    }
+
+    // allocate three descriptors (they need not be contiguous).
+    // disk transfers always use three descriptors.
+    fn alloc3_desc(&mut self) -> Option<[usize; 3]> {
+        let mut idx = [0; 3];
+        for i in 0..3 {
+            match self.alloc_desc() {
+                Some(d) => idx[i] = d,
+                None => {
+                    for &d in &idx[..i] {
+                        self.free_desc(d);
+                    }
+                    return None;
+                }
+            }
+        }
+        Some(idx)
+    }
+}
+
+// the channel to sleep on while waiting for free descriptors.
+fn free_chan() -> usize {
+    &DISK as *const _ as usize
+}
+
+pub(crate) fn init() {
+    let mut disk = DISK.lock();
+
+    if read_reg(VIRTIO_MMIO_MAGIC_VALUE) != 0x74726976
+        || read_reg(VIRTIO_MMIO_VERSION) != 2
+        || read_reg(VIRTIO_MMIO_DEVICE_ID) != 2
+        || read_reg(VIRTIO_MMIO_VENDOR_ID) != 0x554d4551
+    {
+        panic!("could not find virtio disk");
+    }
+
+    // reset device
+    let mut status = 0;
+    write_reg(VIRTIO_MMIO_STATUS, status);
+
+    // set ACKNOWLEDGE status bit
+    status |= VIRTIO_CONFIG_S_ACKNOWLEDGE;
+    write_reg(VIRTIO_MMIO_STATUS, status);
+
+    // set DRIVER status bit
+    status |= VIRTIO_CONFIG_S_DRIVER;
+    write_reg(VIRTIO_MMIO_STATUS, status);
+
+    // negotiate features
+    let mut features = read_reg(VIRTIO_MMIO_DEVICE_FEATURES);
+    features &= !(1 << VIRTIO_BLK_F_RO);
+    features &= !(1 << VIRTIO_BLK_F_SCSI);
+    features &= !(1 << VIRTIO_BLK_F_CONFIG_WCE);
+    features &= !(1 << VIRTIO_BLK_F_MQ);
+    features &= !(1 << VIRTIO_F_ANY_LAYOUT);
+    features &= !(1 << VIRTIO_RING_F_EVENT_IDX);
+    features &= !(1 << VIRTIO_RING_F_INDIRECT_DESC);
+    write_reg(VIRTIO_MMIO_DRIVER_FEATURES, features);
+
+    // tell device that feature negotiation is complete.
+    status |= VIRTIO_CONFIG_S_FEATURES_OK;
+    write_reg(VIRTIO_MMIO_STATUS, status);
+
+    // re-read status to ensure FEATURES_OK is set.
+    status = read_reg(VIRTIO_MMIO_STATUS);
+    if status & VIRTIO_CONFIG_S_FEATURES_OK == 0 {
+        panic!("virtio disk FEATURES_OK unset");
+    }
+
+    // initialize queue 0.
+    write_reg(VIRTIO_MMIO_QUEUE_SEL, 0);
+
+    // ensure queue 0 is not in use.
+    if read_reg(VIRTIO_MMIO_QUEUE_READY) != 0 {
+        panic!("virtio disk should not be ready");
+    }
+
+    // check maximum queue size.
+    let max = read_reg(VIRTIO_MMIO_QUEUE_NUM_MAX);
+    if max == 0 {
+        panic!("virtio disk has no queue 0");
+    }
+    if (max as usize) < NUM {
+        panic!("virtio disk max queue too short");
+    }
+
+    // allocate and zero queue memory.
+    let alloc_page = || {
+        let page = KALLOC
+            .alloc()
+            .unwrap_or_else(|| panic!("virtio disk kalloc"));
+        unsafe {
+            ptr::write_bytes(page as *mut u8, 0, PGSIZE as usize);
+        }
+        page
+    };
+    disk.desc = alloc_page() as *mut VirtqDesc;
+    disk.avail = alloc_page() as *mut VirtqAvail;
+    disk.used = alloc_page() as *mut VirtqUsed;
+
+    // set queue size.
+    write_reg(VIRTIO_MMIO_QUEUE_NUM, NUM as u32);
+
+    // write physical addresses.
+    let desc = disk.desc as u64;
+    let avail = disk.avail as u64;
+    let used = disk.used as u64;
+    write_reg(VIRTIO_MMIO_QUEUE_DESC_LOW, desc as u32);
+    write_reg(VIRTIO_MMIO_QUEUE_DESC_HIGH, (desc >> 32) as u32);
+    write_reg(VIRTIO_MMIO_DRIVER_DESC_LOW, avail as u32);
+    write_reg(VIRTIO_MMIO_DRIVER_DESC_HIGH, (avail >> 32) as u32);
+    write_reg(VIRTIO_MMIO_DEVICE_DESC_LOW, used as u32);
+    write_reg(VIRTIO_MMIO_DEVICE_DESC_HIGH, (used >> 32) as u32);
+
+    // queue is ready.
+    write_reg(VIRTIO_MMIO_QUEUE_READY, 0x1);
+
+    // all NUM descriptors start out unused.
+    disk.free = [true; NUM];
+
+    // tell device we're completely ready.
+    status |= VIRTIO_CONFIG_S_DRIVER_OK;
+    write_reg(VIRTIO_MMIO_STATUS, status);
+
+    // plic.rs and trap/mod.rs arrange for interrupts from VIRTIO0_IRQ.
+}
+
+// read buf.len() bytes from the disk, starting at sector.
+// buf must be identity mapped (not on a kernel stack),
+// since the device writes it by physical address.
+pub(crate) fn read(sector: u64, buf: &mut [u8]) {
+    rw(sector, buf.as_mut_ptr() as u64, buf.len(), false);
+}
+
+// write buf to the disk, starting at sector.
+// buf must be identity mapped, as for read().
+pub(crate) fn write(sector: u64, buf: &[u8]) {
+    rw(sector, buf.as_ptr() as u64, buf.len(), true);
+}
+
+// start a disk request and sleep until the device has finished it.
+fn rw(sector: u64, addr: u64, len: usize, write: bool) {
+    assert_eq!(len % SECTOR_SIZE, 0, "virtio disk: partial sector");
+
+    let mut disk = DISK.lock();
+
+    // the spec's Section 5.2 says that legacy block operations use
+    // three descriptors: one for type/reserved/sector, one for the
+    // data, one for a 1-byte status result.
+
+    // allocate the three descriptors.
+    let idx = loop {
+        if let Some(idx) = disk.alloc3_desc() {
+            break idx;
+        }
+        disk = unsafe { PMASTER.sleep(free_chan(), disk) };
+    };
+
+    // format the three descriptors.
+    // qemu's virtio-blk.c reads them.
+    disk.ops[idx[0]] = VirtioBlkReq {
+        typ: if write {
+            VIRTIO_BLK_T_OUT // write the disk
+        } else {
+            VIRTIO_BLK_T_IN // read the disk
+        },
+        reserved: 0,
+        sector,
+    };
+
+    let op = &disk.ops[idx[0]] as *const VirtioBlkReq as u64;
+    let status = &disk.info[idx[0]].status as *const u8 as u64;
+    unsafe {
+        write_volatile(
+            disk.desc.add(idx[0]),
+            VirtqDesc {
+                addr: op,
+                len: size_of::<VirtioBlkReq>() as u32,
+                flags: VRING_DESC_F_NEXT,
+                next: idx[1] as u16,
+            },
+        );
+        write_volatile(
+            disk.desc.add(idx[1]),
+            VirtqDesc {
+                addr,
+                len: len as u32,
+                flags: VRING_DESC_F_NEXT
+                    | if write {
+                        0 // device reads the data
+                    } else {
+                        VRING_DESC_F_WRITE // device writes the data
+                    },
+                next: idx[2] as u16,
+            },
+        );
+        write_volatile(
+            disk.desc.add(idx[2]),
+            VirtqDesc {
+                addr: status,
+                len: 1,
+                flags: VRING_DESC_F_WRITE, // device writes the status
+                next: 0,
+            },
+        );
+    }
+
+    // device writes 0 on success.
+    disk.info[idx[0]].status = 0xff;
+    disk.info[idx[0]].done = false;
+
+    // tell the device the first index in our chain of descriptors.
+    unsafe {
+        let avail = &mut *disk.avail;
+        let slot = avail.idx as usize % NUM;
+        write_volatile(&mut avail.ring[slot], idx[0] as u16);
+
+        fence(Ordering::SeqCst);
+
+        // tell the device another avail ring entry is available.
+        write_volatile(&mut avail.idx, avail.idx.wrapping_add(1));
+
+        fence(Ordering::SeqCst);
+    }
+
+    write_reg(VIRTIO_MMIO_QUEUE_NOTIFY, 0); // value is queue number
+
+    // Wait for intr() to say request has finished.
+    let chan = &disk.info[idx[0]] as *const Info as usize;
+    while !disk.info[idx[0]].done {
+        disk = unsafe { PMASTER.sleep(chan, disk) };
+    }
+
+    disk.free_chain(idx[0]);
+}
+
+// handle a virtio disk interrupt, raised when the device
+// has finished one or more requests. called from the trap handler.
+pub(crate) fn intr() {
+    let mut disk = DISK.lock();
+
+    // the device won't raise another interrupt until we tell it
+    // we've seen this interrupt, which the following line does.
+    // this may race with the device writing new entries to
+    // the "used" ring, in which case we may process the new
+    // completion entries in this interrupt, and have nothing to do
+    // in the next interrupt, which is harmless.
+    write_reg(
+        VIRTIO_MMIO_INTERRUPT_ACK,
+        read_reg(VIRTIO_MMIO_INTERRUPT_STATUS) & 0x3,
+    );
+
+    fence(Ordering::SeqCst);
+
+    // the device increments disk.used.idx when it
+    // adds an entry to the used ring.
+    while disk.used_idx != unsafe { read_volatile(&(*disk.used).idx) } {
+        fence(Ordering::SeqCst);
+        let slot = disk.used_idx as usize % NUM;
+        let id = unsafe { read_volatile(&(*disk.used).ring[slot].id) } as usize;
+
+        let info = &mut disk.info[id];
+        if unsafe { read_volatile(&info.status) } != 0 {
+            panic!("virtio disk intr status");
+        }
+
+        // disk is done with the request.
+        info.done = true;
+        unsafe {
+            PMASTER.wakeup(info as *const Info as usize);
+        }
+
+        disk.used_idx = disk.used_idx.wrapping_add(1);
+    }
+}
@@ -0,0 +1,70 @@
+// Format of an ELF executable file
+
+use core::{mem::size_of, slice};
+
+pub(crate) const ELF_MAGIC: u32 = 0x464C457F; // "\x7FELF" in little endian
+const ELF_CLASS64: u8 = 2; // e_ident[EI_CLASS]
+const ELF_DATA2LSB: u8 = 1; // e_ident[EI_DATA]
+const ET_EXEC: u16 = 2; // executable file
+const EM_RISCV: u16 = 243; // RISC-V machine
+
+// Values for ProgHeader::typ
+pub(crate) const ELF_PROG_LOAD: u32 = 1;
+
+// Flag bits for ProgHeader::flags
+pub(crate) const ELF_PROG_FLAG_EXEC: u32 = 1;
+pub(crate) const ELF_PROG_FLAG_WRITE: u32 = 2;
+pub(crate) const ELF_PROG_FLAG_READ: u32 = 4;
+
+// File header
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct ElfHeader {
+    pub(crate) magic: u32, // must equal ELF_MAGIC
+    pub(crate) elf: [u8; 12],
+    pub(crate) typ: u16,
+    pub(crate) machine: u16,
+    pub(crate) version: u32,
+    pub(crate) entry: u64,
+    pub(crate) phoff: u64,
+    pub(crate) shoff: u64,
+    pub(crate) flags: u32,
+    pub(crate) ehsize: u16,
+    pub(crate) phentsize: u16,
+    pub(crate) phnum: u16,
+    pub(crate) shentsize: u16,
+    pub(crate) shnum: u16,
+    pub(crate) shstrndx: u16,
+}
+
+// Program section header
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct ProgHeader {
+    pub(crate) typ: u32,
+    pub(crate) flags: u32,
+    pub(crate) off: u64,
+    pub(crate) vaddr: u64,
+    pub(crate) paddr: u64,
+    pub(crate) filesz: u64,
+    pub(crate) memsz: u64,
+    pub(crate) align: u64,
+}
+
+impl ElfHeader {
+    // a 64-bit little-endian RISC-V executable
+    // whose program headers we know how to read.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.magic == ELF_MAGIC
+            && self.elf[0] == ELF_CLASS64
+            && self.elf[1] == ELF_DATA2LSB
+            && self.typ == ET_EXEC
+            && self.machine == EM_RISCV
+            && self.phentsize as usize == size_of::<ProgHeader>()
+    }
+}
+
+// view a header as raw bytes so it can be read in from an image.
+pub(crate) fn as_bytes_mut<T>(header: &mut T) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(header as *mut T as *mut u8, size_of::<T>()) }
+}
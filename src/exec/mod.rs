@@ -0,0 +1,175 @@
+use core::cmp::min;
+use core::mem::{size_of, size_of_val};
+use core::slice;
+
+use crate::arch::{pg_round_up, MAXARG, PGSIZE, USERSTACK};
+use crate::fs::dir::namei;
+use crate::fs::inode::InodeData;
+use crate::memory::vm::{PageTable, PTE_R, PTE_W, PTE_X};
+use crate::process::master::{PMaster, PMASTER};
+use crate::syscall::Errno;
+
+use self::elf::{
+    as_bytes_mut, ElfHeader, ProgHeader, ELF_PROG_FLAG_EXEC, ELF_PROG_FLAG_READ,
+    ELF_PROG_FLAG_WRITE, ELF_PROG_LOAD,
+};
+
+pub(crate) mod elf;
+
+// Replace the current process's memory image with the executable at path.
+// The old image is only released once the new one is fully built,
+// so on failure the caller keeps running unchanged.
+// Return argc, which ends up in a0, the first argument to user main(argc, argv).
+pub(crate) fn exec(path: &[u8], argv: &[&[u8]]) -> Result<u64, Errno> {
+    let ip = namei(path).ok_or(Errno::ENOENT)?;
+    let mut image = ip.lock();
+
+    // Check ELF header
+    let mut elf = ElfHeader::default();
+    if image.read_at(as_bytes_mut(&mut elf), 0) != Some(size_of::<ElfHeader>()) || !elf.is_valid() {
+        return Err(Errno::ENOEXEC);
+    }
+
+    let p = unsafe { &mut PMASTER.my_proc().context };
+    let pagetable = PMaster::proc_pagetable(p.trapframe).ok_or(Errno::ENOMEM)?;
+    let mut sz = 0;
+    let sp = match load(&mut image, &elf, pagetable, &mut sz, argv) {
+        Some(sp) => sp,
+        None => {
+            PMaster::proc_freepagetable(pagetable, sz);
+            return Err(Errno::ENOEXEC);
+        }
+    };
+    drop(image);
+    drop(ip);
+
+    // Save program name for debugging.
+    let name = path.rsplit(|&c| c == b'/').next().unwrap_or(path);
+    p.set_name(name);
+
+    // Commit to the user image.
+    let old_pagetable = p.pagetable;
+    let old_sz = p.sz;
+    p.pagetable = pagetable;
+    p.sz = sz;
+    unsafe {
+        (*p.trapframe).epc = elf.entry; // initial program counter = main
+        (*p.trapframe).sp = sp; // initial stack pointer
+        (*p.trapframe).a1 = sp; // argv, the second argument to main
+    }
+    PMaster::proc_freepagetable(old_pagetable, old_sz);
+
+    Ok(argv.len() as u64)
+}
+
+// Build the new image in pagetable: load every program segment,
+// then allocate the user stack and push the argument strings.
+// sz tracks how much user memory has been mapped, so the caller
+// can free it if this fails half way.
+// Return the initial stack pointer, which is also the user address of argv.
+fn load(
+    image: &mut InodeData,
+    elf: &ElfHeader,
+    pagetable: u64,
+    sz: &mut u64,
+    argv: &[&[u8]],
+) -> Option<u64> {
+    if argv.len() > MAXARG {
+        return None;
+    }
+
+    // Load program into memory.
+    for i in 0..elf.phnum as u64 {
+        let off = elf.phoff + i * size_of::<ProgHeader>() as u64;
+        let mut ph = ProgHeader::default();
+        if image.read_at(as_bytes_mut(&mut ph), off)? != size_of::<ProgHeader>() {
+            return None;
+        }
+        if ph.typ != ELF_PROG_LOAD {
+            continue;
+        }
+        if ph.memsz < ph.filesz {
+            return None;
+        }
+        let end = ph.vaddr.checked_add(ph.memsz)?;
+        if ph.vaddr % PGSIZE != 0 {
+            return None;
+        }
+        *sz = PageTable::uvmalloc(pagetable, *sz, end, flags2perm(ph.flags))?;
+        loadseg(pagetable, ph.vaddr, image, ph.off, ph.filesz)?;
+    }
+
+    // Allocate some pages at the next page boundary.
+    // Make the first inaccessible as a stack guard.
+    // Use the rest as the user stack.
+    let base = pg_round_up(*sz);
+    *sz = PageTable::uvmalloc(
+        pagetable,
+        base,
+        base + (USERSTACK + 1) * PGSIZE,
+        PTE_R | PTE_W,
+    )?;
+    PageTable::uvmclear(pagetable, base);
+    let mut sp = *sz;
+    let stackbase = sp - USERSTACK * PGSIZE;
+
+    // Push argument strings, prepare rest of stack in ustack.
+    let mut ustack = [0u64; MAXARG + 1];
+    for (i, arg) in argv.iter().enumerate() {
+        sp -= arg.len() as u64 + 1;
+        sp -= sp % 16; // riscv sp must be 16-byte aligned
+        if sp < stackbase {
+            return None;
+        }
+        PageTable::copyout(pagetable, sp, arg).ok()?;
+        PageTable::copyout(pagetable, sp + arg.len() as u64, &[0]).ok()?;
+        ustack[i] = sp;
+    }
+    ustack[argv.len()] = 0;
+
+    // push the array of argv[] pointers.
+    let ustack = &ustack[..argv.len() + 1];
+    sp -= size_of_val(ustack) as u64;
+    sp -= sp % 16;
+    if sp < stackbase {
+        return None;
+    }
+    let bytes = unsafe { slice::from_raw_parts(ustack.as_ptr() as *const u8, size_of_val(ustack)) };
+    PageTable::copyout(pagetable, sp, bytes).ok()?;
+
+    Some(sp)
+}
+
+// map ELF segment flags to PTE permissions.
+fn flags2perm(flags: u32) -> u64 {
+    let mut perm = 0;
+    if flags & ELF_PROG_FLAG_READ != 0 {
+        perm |= PTE_R;
+    }
+    if flags & ELF_PROG_FLAG_WRITE != 0 {
+        // writable pages must also be readable in RISC-V.
+        perm |= PTE_R | PTE_W;
+    }
+    if flags & ELF_PROG_FLAG_EXEC != 0 {
+        perm |= PTE_X;
+    }
+    perm
+}
+
+// Load a program segment into pagetable at virtual address va.
+// va must be page-aligned
+// and the pages from va to va+sz must already be mapped.
+fn loadseg(pagetable: u64, va: u64, image: &mut InodeData, offset: u64, sz: u64) -> Option<()> {
+    let mut pagetable = PageTable::from_addr(pagetable);
+    let mut i = 0;
+    while i < sz {
+        let pa = pagetable.translate(va + i).ok()?;
+        let n = min(sz - i, PGSIZE);
+        let dst = unsafe { slice::from_raw_parts_mut(pa as *mut u8, n as usize) };
+        if image.read_at(dst, offset + i)? != n as usize {
+            return None;
+        }
+        i += PGSIZE;
+    }
+    Some(())
+}
@@ -0,0 +1,243 @@
+// Buffer cache.
+//
+// The buffer cache holds cached copies of disk block contents.
+// Caching disk blocks in memory reduces the number of disk reads
+// and also provides a synchronization point for disk blocks used
+// by multiple processes.
+//
+// Interface:
+// * To get a buffer for a particular disk block, call bread.
+// * After changing buffer data, call bwrite to write it to disk.
+// * When done with the buffer, drop the guard (xv6's brelse).
+// * Only one process at a time can use a buffer,
+//     so do not keep them longer than necessary.
+//
+// The bookkeeping (which block a buffer holds, reference counts and
+// the LRU order) lives under the BCACHE spinlock; the block contents
+// live under a per-buffer sleep lock, held across disk I/O.
+
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut, Drop};
+
+use crate::arch::NBUF;
+use crate::driver::virtio_disk::{self, SECTOR_SIZE};
+use crate::fs::layout::BSIZE;
+use crate::lock::sleeplock::{SleepGuard, SleepLock};
+use crate::lock::spinlock::SpinLock;
+
+pub(crate) struct Buf {
+    valid: bool, // has data been read from disk?
+    dev: u32,
+    blockno: u32,
+    pub(crate) data: [u8; BSIZE],
+}
+
+impl Buf {
+    const fn new() -> Self {
+        Self {
+            valid: false,
+            dev: 0,
+            blockno: 0,
+            data: [0; BSIZE],
+        }
+    }
+
+    pub(crate) fn blockno(&self) -> u32 {
+        self.blockno
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    dev: u32,
+    blockno: u32,
+    refcnt: u32,
+    prev: usize,
+    next: usize,
+}
+
+struct BCache {
+    // Linked list of all buffers, through prev/next.
+    // Sorted by how recently the buffer was used.
+    // list[HEAD].next is most recent, list[HEAD].prev is least.
+    list: [Entry; NBUF + 1],
+    hits: u64,
+    misses: u64,
+}
+
+const HEAD: usize = NBUF;
+
+static BCACHE: SpinLock<BCache> = SpinLock::new(BCache::new());
+
+// buffer contents, indexed like BCache.list.
+// statics are identity mapped, so the disk can DMA into them.
+static BUFS: [SleepLock<Buf>; NBUF] = [const { SleepLock::new(Buf::new()) }; NBUF];
+
+impl BCache {
+    const fn new() -> Self {
+        let mut list = [Entry {
+            dev: 0,
+            blockno: 0,
+            refcnt: 0,
+            prev: HEAD,
+            next: HEAD,
+        }; NBUF + 1];
+
+        // Create linked list of buffers
+        let mut i = 0;
+        while i < NBUF {
+            list[i].next = list[HEAD].next;
+            list[i].prev = HEAD;
+            let next = list[HEAD].next;
+            list[next].prev = i;
+            list[HEAD].next = i;
+            i += 1;
+        }
+
+        Self {
+            list,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn unlink(&mut self, i: usize) {
+        let Entry { prev, next, .. } = self.list[i];
+        self.list[next].prev = prev;
+        self.list[prev].next = next;
+    }
+
+    // insert buffer i at the most recently used end.
+    fn push_front(&mut self, i: usize) {
+        let first = self.list[HEAD].next;
+        self.list[i].next = first;
+        self.list[i].prev = HEAD;
+        self.list[first].prev = i;
+        self.list[HEAD].next = i;
+    }
+}
+
+// A locked buffer, returned by bread.
+// Dropping it releases the buffer.
+pub(crate) struct BufGuard {
+    index: usize,
+    buf: ManuallyDrop<SleepGuard<'static, Buf>>,
+}
+
+impl Deref for BufGuard {
+    type Target = Buf;
+    fn deref(&self) -> &Buf {
+        &self.buf
+    }
+}
+
+impl DerefMut for BufGuard {
+    fn deref_mut(&mut self) -> &mut Buf {
+        &mut self.buf
+    }
+}
+
+// Release a locked buffer.
+// Move to the head of the most-recently-used list.
+impl Drop for BufGuard {
+    fn drop(&mut self) {
+        // release the sleep lock before giving up the reference,
+        // so a recycler never waits on a buffer nobody uses.
+        unsafe {
+            ManuallyDrop::drop(&mut self.buf);
+        }
+
+        let mut bcache = BCACHE.lock();
+        let entry = &mut bcache.list[self.index];
+        entry.refcnt -= 1;
+        if entry.refcnt == 0 {
+            // no one is waiting for it.
+            bcache.unlink(self.index);
+            bcache.push_front(self.index);
+        }
+    }
+}
+
+// Look through buffer cache for block on device dev.
+// If not found, allocate a buffer.
+// In either case, return locked buffer.
+fn bget(dev: u32, blockno: u32) -> BufGuard {
+    let mut bcache = BCACHE.lock();
+
+    // Is the block already cached?
+    let mut i = bcache.list[HEAD].next;
+    while i != HEAD {
+        let entry = &mut bcache.list[i];
+        if entry.dev == dev && entry.blockno == blockno {
+            entry.refcnt += 1;
+            bcache.hits += 1;
+            drop(bcache);
+            return lock(i);
+        }
+        i = entry.next;
+    }
+
+    // Not cached.
+    // Recycle the least recently used (LRU) unused buffer.
+    let mut i = bcache.list[HEAD].prev;
+    while i != HEAD {
+        let entry = &mut bcache.list[i];
+        if entry.refcnt == 0 {
+            entry.dev = dev;
+            entry.blockno = blockno;
+            entry.refcnt = 1;
+            bcache.misses += 1;
+            drop(bcache);
+            return lock(i);
+        }
+        i = entry.prev;
+    }
+
+    panic!("bget: no buffers");
+}
+
+fn lock(index: usize) -> BufGuard {
+    BufGuard {
+        index,
+        buf: ManuallyDrop::new(BUFS[index].lock()),
+    }
+}
+
+// Return a locked buf with the contents of the indicated block.
+pub(crate) fn bread(dev: u32, blockno: u32) -> BufGuard {
+    let mut b = bget(dev, blockno);
+    // the buffer may still hold the contents of the block it was
+    // recycled from; the identity check catches that.
+    if !(b.valid && b.dev == dev && b.blockno == blockno) {
+        virtio_disk::read(sector(blockno), &mut b.data);
+        b.valid = true;
+        b.dev = dev;
+        b.blockno = blockno;
+    }
+    b
+}
+
+// Write b's contents to disk.
+pub(crate) fn bwrite(b: &mut BufGuard) {
+    virtio_disk::write(sector(b.blockno), &b.data);
+}
+
+// Hold an extra reference so the buffer is not recycled,
+// keeping the block cached until bunpin.
+pub(crate) fn bpin(b: &BufGuard) {
+    BCACHE.lock().list[b.index].refcnt += 1;
+}
+
+pub(crate) fn bunpin(b: &BufGuard) {
+    BCACHE.lock().list[b.index].refcnt -= 1;
+}
+
+// (hits, misses) of bget since boot, for tuning NBUF.
+pub(crate) fn stats() -> (u64, u64) {
+    let bcache = BCACHE.lock();
+    (bcache.hits, bcache.misses)
+}
+
+fn sector(blockno: u32) -> u64 {
+    blockno as u64 * (BSIZE / SECTOR_SIZE) as u64
+}
@@ -0,0 +1,148 @@
+// Directories and path names.
+
+use core::mem::size_of;
+
+use super::inode::{iget, Inode, InodeData};
+use super::layout::{as_bytes, as_bytes_mut, Dirent, DIRSIZ, ROOTINO, T_DIR};
+use crate::arch::ROOTDEV;
+use crate::process::master::PMASTER;
+
+// names are compared up to DIRSIZ bytes, as stored on disk.
+fn namecmp(s: &[u8], t: &[u8]) -> bool {
+    s[..s.len().min(DIRSIZ)] == t[..t.len().min(DIRSIZ)]
+}
+
+const DIRENT_SIZE: u32 = size_of::<Dirent>() as u32;
+
+impl InodeData {
+    // Look for a directory entry in a directory.
+    // If found, return the entry's inode and its byte offset.
+    pub(crate) fn dirlookup(&mut self, name: &[u8]) -> Option<(Inode, u32)> {
+        if self.typ != T_DIR {
+            panic!("dirlookup not DIR");
+        }
+
+        let mut off = 0;
+        while off < self.size {
+            let mut de = Dirent::default();
+            if self.read(as_bytes_mut(&mut de), off) != size_of::<Dirent>() {
+                panic!("dirlookup read");
+            }
+            if de.inum != 0 && namecmp(name, de.name()) {
+                // entry matches path element
+                return Some((iget(self.dev, de.inum as u32), off));
+            }
+            off += DIRENT_SIZE;
+        }
+
+        None
+    }
+
+    // Write a new directory entry (name, inum) into the directory.
+    // Returns None on failure (e.g. out of disk blocks).
+    pub(crate) fn dirlink(&mut self, name: &[u8], inum: u32) -> Option<()> {
+        // Check that name is not present.
+        if self.dirlookup(name).is_some() {
+            return None;
+        }
+
+        // Look for an empty dirent.
+        let mut de = Dirent::default();
+        let mut off = 0;
+        while off < self.size {
+            if self.read(as_bytes_mut(&mut de), off) != size_of::<Dirent>() {
+                panic!("dirlink read");
+            }
+            if de.inum == 0 {
+                break;
+            }
+            off += DIRENT_SIZE;
+        }
+
+        de.set_name(name);
+        de.inum = inum as u16;
+        if self.write(as_bytes(&de), off)? != size_of::<Dirent>() {
+            return None;
+        }
+        Some(())
+    }
+
+    // Is the directory empty except for "." and ".." ?
+    pub(crate) fn is_dir_empty(&mut self) -> bool {
+        let mut off = 2 * DIRENT_SIZE;
+        while off < self.size {
+            let mut de = Dirent::default();
+            if self.read(as_bytes_mut(&mut de), off) != size_of::<Dirent>() {
+                panic!("isdirempty: readi");
+            }
+            if de.inum != 0 {
+                return false;
+            }
+            off += DIRENT_SIZE;
+        }
+        true
+    }
+}
+
+// Paths
+
+// Split the next path element off of path.
+// Return the element and the remainder, with leading slashes
+// skipped from both, or None if there is no element to remove.
+//
+// Examples:
+//   skipelem("a/bb/c") = ("a", "bb/c")
+//   skipelem("///a//bb") = ("a", "bb")
+//   skipelem("a") = ("a", "")
+//   skipelem("") = skipelem("////") = None
+fn skipelem(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    let start = path.iter().position(|&c| c != b'/')?;
+    let path = &path[start..];
+    let len = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+    let (name, rest) = path.split_at(len);
+    let skip = rest.iter().position(|&c| c != b'/').unwrap_or(rest.len());
+    Some((name, &rest[skip..]))
+}
+
+// Look up and return the inode for a path name.
+// If parent is true, return the inode for the parent and also
+// the final path element.
+fn namex(path: &[u8], parent: bool) -> Option<(Inode, &[u8])> {
+    let mut ip = if path.first() == Some(&b'/') {
+        iget(ROOTDEV, ROOTINO)
+    } else {
+        let p = unsafe { &PMASTER.my_proc().context };
+        p.cwd.clone().unwrap_or_else(|| panic!("namex: no cwd"))
+    };
+
+    let mut path = path;
+    let mut name: &[u8] = &[];
+    while let Some((elem, rest)) = skipelem(path) {
+        name = elem;
+        let mut dp = ip.lock();
+        if dp.typ != T_DIR {
+            return None;
+        }
+        if parent && rest.is_empty() {
+            // Stop one level early.
+            drop(dp);
+            return Some((ip, name));
+        }
+        let (next, _) = dp.dirlookup(name)?;
+        drop(dp);
+        ip = next;
+        path = rest;
+    }
+    if parent {
+        return None;
+    }
+    Some((ip, name))
+}
+
+pub(crate) fn namei(path: &[u8]) -> Option<Inode> {
+    namex(path, false).map(|(ip, _)| ip)
+}
+
+pub(crate) fn nameiparent(path: &[u8]) -> Option<(Inode, &[u8])> {
+    namex(path, true)
+}
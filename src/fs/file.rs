@@ -0,0 +1,236 @@
+// Support functions for system calls that involve file descriptors.
+//
+// An open file is a slot in FTABLE, shared by every file descriptor
+// that refers to it. A File is a counted reference to a slot:
+// cloning it is filedup() and dropping it is fileclose().
+
+use core::cmp::min;
+use core::mem;
+
+use super::inode::Inode;
+use super::layout::{as_bytes, BSIZE};
+use super::log::{begin_op, end_op};
+use super::pipe::Pipe;
+use crate::arch::{MAXOPBLOCKS, NDEV, NFILE};
+use crate::driver::console;
+use crate::lock::spinlock::SpinLock;
+use crate::memory::vm::PageTable;
+use crate::process::master::PMASTER;
+use crate::syscall::Errno;
+
+#[derive(Clone)]
+pub(crate) enum FileType {
+    None,
+    Pipe(Pipe),
+    Inode(Inode),
+    Device(Inode, i16), // inode and major device number
+}
+
+struct Entry {
+    refcnt: usize, // reference count
+    readable: bool,
+    writable: bool,
+    typ: FileType,
+    off: u32, // FileType::Inode only
+}
+
+static FTABLE: SpinLock<[Entry; NFILE]> = SpinLock::new(
+    [const {
+        Entry {
+            refcnt: 0,
+            readable: false,
+            writable: false,
+            typ: FileType::None,
+            off: 0,
+        }
+    }; NFILE],
+);
+
+// map major device number to device functions.
+pub(crate) struct Devsw {
+    read: fn(u64, usize) -> Result<usize, Errno>,
+    write: fn(u64, usize) -> Result<usize, Errno>,
+}
+
+pub(crate) const CONSOLE: usize = 1;
+
+static DEVSW: [Option<Devsw>; NDEV] = {
+    let mut devsw = [const { None }; NDEV];
+    // connect read and write system calls
+    // to console::read and console::write.
+    devsw[CONSOLE] = Some(Devsw {
+        read: console::read,
+        write: console::write,
+    });
+    devsw
+};
+
+fn devsw(major: i16) -> Result<&'static Devsw, Errno> {
+    usize::try_from(major)
+        .ok()
+        .and_then(|major| DEVSW.get(major)?.as_ref())
+        .ok_or(Errno::ENXIO)
+}
+
+pub(crate) struct File {
+    index: usize,
+}
+
+impl File {
+    // Allocate a file structure.
+    pub(crate) fn alloc(typ: FileType, readable: bool, writable: bool) -> Option<File> {
+        let mut ftable = FTABLE.lock();
+        let index = ftable.iter().position(|f| f.refcnt == 0)?;
+        ftable[index] = Entry {
+            refcnt: 1,
+            readable,
+            writable,
+            typ,
+            off: 0,
+        };
+        Some(File { index })
+    }
+
+    // Get metadata about file f.
+    // addr is a user virtual address, pointing to a struct stat.
+    pub(crate) fn stat(&self, addr: u64) -> Result<(), Errno> {
+        let ip = match &FTABLE.lock()[self.index].typ {
+            FileType::Inode(ip) | FileType::Device(ip, _) => ip.clone(),
+            FileType::Pipe(_) | FileType::None => return Err(Errno::EINVAL),
+        };
+        let st = ip.lock().stat();
+        let pagetable = unsafe { PMASTER.my_proc().context.pagetable };
+        Ok(PageTable::copyout(pagetable, addr, as_bytes(&st))?)
+    }
+
+    // Read from file f.
+    // addr is a user virtual address.
+    pub(crate) fn read(&self, addr: u64, n: usize) -> Result<usize, Errno> {
+        let (readable, typ) = {
+            let f = &FTABLE.lock()[self.index];
+            (f.readable, f.typ.clone())
+        };
+        if !readable {
+            return Err(Errno::EBADF);
+        }
+
+        match typ {
+            FileType::Pipe(pi) => pi.read(addr, n),
+            FileType::Device(_, major) => (devsw(major)?.read)(addr, n),
+            FileType::Inode(ip) => {
+                let mut guard = ip.lock();
+                let off = FTABLE.lock()[self.index].off;
+                let r = guard.read_user(addr, off, n).ok_or(Errno::EFAULT)?;
+                FTABLE.lock()[self.index].off = off + r as u32;
+                Ok(r)
+            }
+            FileType::None => panic!("fileread"),
+        }
+    }
+
+    // Write to file f.
+    // addr is a user virtual address.
+    pub(crate) fn write(&self, addr: u64, n: usize) -> Result<usize, Errno> {
+        let (writable, typ) = {
+            let f = &FTABLE.lock()[self.index];
+            (f.writable, f.typ.clone())
+        };
+        if !writable {
+            return Err(Errno::EBADF);
+        }
+
+        match typ {
+            FileType::Pipe(pi) => pi.write(addr, n),
+            FileType::Device(_, major) => (devsw(major)?.write)(addr, n),
+            FileType::Inode(ip) => {
+                // write a few blocks at a time to avoid exceeding
+                // the maximum log transaction size, including
+                // i-node, indirect block, allocation blocks,
+                // and 2 blocks of slop for non-aligned writes.
+                let max = ((MAXOPBLOCKS - 1 - 1 - 2) / 2) * BSIZE;
+                let mut i = 0;
+                while i < n {
+                    let n1 = min(n - i, max);
+
+                    begin_op();
+                    let mut guard = ip.lock();
+                    let off = FTABLE.lock()[self.index].off;
+                    let r = guard.write_user(addr + i as u64, off, n1);
+                    if let Some(r) = r {
+                        FTABLE.lock()[self.index].off = off + r as u32;
+                    }
+                    drop(guard);
+                    end_op();
+
+                    if r != Some(n1) {
+                        // error from writei
+                        break;
+                    }
+                    i += n1;
+                }
+                if i == n {
+                    Ok(n)
+                } else {
+                    Err(Errno::EIO)
+                }
+            }
+            FileType::None => panic!("filewrite"),
+        }
+    }
+}
+
+// Allocate a pipe and a pair of files for its read and write ends.
+pub(crate) fn pipealloc() -> Option<(File, File)> {
+    let pi = Pipe::alloc()?;
+    let Some(rf) = File::alloc(FileType::Pipe(pi), true, false) else {
+        pi.close(false);
+        pi.close(true);
+        return None;
+    };
+    let Some(wf) = File::alloc(FileType::Pipe(pi), false, true) else {
+        drop(rf);
+        pi.close(true);
+        return None;
+    };
+    Some((rf, wf))
+}
+
+// Increment ref count for file f.
+impl Clone for File {
+    fn clone(&self) -> Self {
+        let mut ftable = FTABLE.lock();
+        if ftable[self.index].refcnt < 1 {
+            panic!("filedup");
+        }
+        ftable[self.index].refcnt += 1;
+        File { index: self.index }
+    }
+}
+
+// Close file f.  (Decrement ref count, close when reaches 0.)
+impl Drop for File {
+    fn drop(&mut self) {
+        let mut ftable = FTABLE.lock();
+        let f = &mut ftable[self.index];
+        if f.refcnt < 1 {
+            panic!("fileclose");
+        }
+        f.refcnt -= 1;
+        if f.refcnt > 0 {
+            return;
+        }
+        let typ = mem::replace(&mut f.typ, FileType::None);
+        let writable = f.writable;
+        drop(ftable);
+
+        match typ {
+            FileType::Pipe(pi) => pi.close(writable),
+            FileType::Inode(ip) | FileType::Device(ip, _) => {
+                begin_op();
+                drop(ip);
+                end_op();
+            }
+            FileType::None => {}
+        }
+    }
+}
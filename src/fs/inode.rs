@@ -0,0 +1,471 @@
+// Inodes.
+//
+// An inode describes a single unnamed file.
+// The inode disk structure holds metadata: the file's type,
+// its size, the number of links referring to it, and the
+// list of blocks holding the file's content.
+//
+// The inodes are laid out sequentially on disk at block
+// sb.inodestart. Each inode has a number, indicating its
+// position on the disk.
+//
+// The kernel keeps a table of in-use inodes in memory
+// to provide a place for synchronizing access
+// to inodes used by multiple processes. The in-memory
+// inodes include book-keeping information that is
+// not stored on disk: the reference count and valid.
+//
+// An Inode is a counted reference to a table entry:
+// iget() hands one out, cloning it is idup(), and dropping it
+// is iput(). Inode::lock() is ilock(); it reads the inode from
+// disk if needed, and dropping the returned guard is iunlock().
+// Since the guard borrows the Inode, it must be unlocked before
+// the reference is put.
+
+use core::cmp::min;
+use core::mem::size_of;
+
+use super::bio::{bread, Buf};
+use super::layout::{
+    as_bytes, as_bytes_mut, iblock, Dinode, BSIZE, IPB, MAXFILE, NDIRECT, NINDIRECT,
+};
+use super::log::log_write;
+use super::{balloc, bfree, sb};
+use crate::arch::NINODE;
+use crate::lock::sleeplock::{SleepGuard, SleepLock};
+use crate::lock::spinlock::SpinLock;
+use crate::memory::vm::PageTable;
+use crate::process::master::PMASTER;
+use crate::{print, println};
+
+// in-memory copy of an inode
+pub(crate) struct InodeData {
+    valid: bool,          // inode has been read from disk?
+    pub(crate) dev: u32,  // Device number
+    pub(crate) inum: u32, // Inode number
+
+    // copy of disk inode
+    pub(crate) typ: i16,
+    pub(crate) major: i16,
+    pub(crate) minor: i16,
+    pub(crate) nlink: i16,
+    pub(crate) size: u32,
+    addrs: [u32; NDIRECT + 1],
+}
+
+// file status, as returned by fstat().
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Stat {
+    pub(crate) dev: i32,   // File system's disk device
+    pub(crate) ino: u32,   // Inode number
+    pub(crate) typ: i16,   // Type of file
+    pub(crate) nlink: i16, // Number of links to file
+    _pad: u32,             // keep size aligned without uninitialized padding
+    pub(crate) size: u64,  // Size of file in bytes
+}
+
+pub(crate) type InodeGuard<'a> = SleepGuard<'a, InodeData>;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    dev: u32,
+    inum: u32,
+    refcnt: u32, // Reference count
+}
+
+static ITABLE: SpinLock<[Entry; NINODE]> = SpinLock::new(
+    [Entry {
+        dev: 0,
+        inum: 0,
+        refcnt: 0,
+    }; NINODE],
+);
+
+// inode contents, indexed like ITABLE.
+static INODES: [SleepLock<InodeData>; NINODE] = [const {
+    SleepLock::new(InodeData {
+        valid: false,
+        dev: 0,
+        inum: 0,
+        typ: 0,
+        major: 0,
+        minor: 0,
+        nlink: 0,
+        size: 0,
+        addrs: [0; NDIRECT + 1],
+    })
+}; NINODE];
+
+pub(crate) struct Inode {
+    index: usize,
+}
+
+// byte offset of inode inum within its block.
+fn dinode_off(inum: u32) -> usize {
+    (inum % IPB) as usize * size_of::<Dinode>()
+}
+
+fn read_dinode(bp: &Buf, inum: u32) -> Dinode {
+    let off = dinode_off(inum);
+    let mut dip = Dinode::default();
+    as_bytes_mut(&mut dip).copy_from_slice(&bp.data[off..off + size_of::<Dinode>()]);
+    dip
+}
+
+fn write_dinode(bp: &mut Buf, inum: u32, dip: &Dinode) {
+    let off = dinode_off(inum);
+    bp.data[off..off + size_of::<Dinode>()].copy_from_slice(as_bytes(dip));
+}
+
+// Allocate an inode on device dev.
+// Mark it as allocated by  giving it type typ.
+// Returns an unlocked but allocated and referenced inode,
+// or None if there is no free inode.
+pub(crate) fn ialloc(dev: u32, typ: i16) -> Option<Inode> {
+    let sb = sb();
+    for inum in 1..sb.ninodes {
+        let mut bp = bread(dev, iblock(inum, &sb));
+        if read_dinode(&bp, inum).typ == 0 {
+            // a free inode
+            let dip = Dinode {
+                typ,
+                ..Default::default()
+            };
+            write_dinode(&mut bp, inum, &dip);
+            log_write(&bp); // mark it allocated on the disk
+            drop(bp);
+            return Some(iget(dev, inum));
+        }
+    }
+    println!("ialloc: no inodes");
+    None
+}
+
+// Find the inode with number inum on device dev
+// and return the in-memory copy. Does not lock
+// the inode and does not read it from disk.
+pub(crate) fn iget(dev: u32, inum: u32) -> Inode {
+    let mut itable = ITABLE.lock();
+
+    // Is the inode already in the table?
+    let mut empty = None;
+    for (i, entry) in itable.iter_mut().enumerate() {
+        if entry.refcnt > 0 && entry.dev == dev && entry.inum == inum {
+            entry.refcnt += 1;
+            return Inode { index: i };
+        }
+        if empty.is_none() && entry.refcnt == 0 {
+            // Remember empty slot.
+            empty = Some(i);
+        }
+    }
+
+    // Recycle an inode entry.
+    let i = empty.unwrap_or_else(|| panic!("iget: no inodes"));
+    itable[i] = Entry {
+        dev,
+        inum,
+        refcnt: 1,
+    };
+    // no one refers to a free entry, so this won't block.
+    let mut ip = INODES[i].lock();
+    ip.dev = dev;
+    ip.inum = inum;
+    ip.valid = false;
+    Inode { index: i }
+}
+
+impl Inode {
+    // Lock the inode.
+    // Reads the inode from disk if necessary.
+    pub(crate) fn lock(&self) -> InodeGuard<'_> {
+        let mut ip = INODES[self.index].lock();
+        if !ip.valid {
+            let bp = bread(ip.dev, iblock(ip.inum, &sb()));
+            let dip = read_dinode(&bp, ip.inum);
+            drop(bp);
+            ip.typ = dip.typ;
+            ip.major = dip.major;
+            ip.minor = dip.minor;
+            ip.nlink = dip.nlink;
+            ip.size = dip.size;
+            ip.addrs = dip.addrs;
+            ip.valid = true;
+            if ip.typ == 0 {
+                panic!("ilock: no type");
+            }
+        }
+        ip
+    }
+}
+
+// Increment reference count for ip.
+impl Clone for Inode {
+    fn clone(&self) -> Self {
+        ITABLE.lock()[self.index].refcnt += 1;
+        Inode { index: self.index }
+    }
+}
+
+// Drop a reference to an in-memory inode.
+// If that was the last reference, the inode table entry can
+// be recycled.
+// If that was the last reference and the inode has no links
+// to it, free the inode (and its content) on disk.
+impl Drop for Inode {
+    fn drop(&mut self) {
+        let mut itable = ITABLE.lock();
+
+        if itable[self.index].refcnt == 1 {
+            // refcnt == 1 means no other process can have ip locked,
+            // so this lock() won't block (or deadlock).
+            let mut ip = INODES[self.index].lock();
+            if ip.valid && ip.nlink == 0 {
+                // inode has no links and no other references: truncate and free.
+                drop(itable);
+                ip.trunc();
+                ip.typ = 0;
+                ip.update();
+                ip.valid = false;
+                drop(ip);
+                itable = ITABLE.lock();
+            }
+        }
+
+        itable[self.index].refcnt -= 1;
+    }
+}
+
+impl InodeData {
+    // Copy a modified in-memory inode to disk.
+    // Must be called after every change to an ip field
+    // that lives on disk.
+    pub(crate) fn update(&self) {
+        let mut bp = bread(self.dev, iblock(self.inum, &sb()));
+        let dip = Dinode {
+            typ: self.typ,
+            major: self.major,
+            minor: self.minor,
+            nlink: self.nlink,
+            size: self.size,
+            addrs: self.addrs,
+        };
+        write_dinode(&mut bp, self.inum, &dip);
+        log_write(&bp);
+    }
+
+    // Inode content
+    //
+    // The content (data) associated with each inode is stored
+    // in blocks on the disk. The first NDIRECT block numbers
+    // are listed in addrs[].  The next NINDIRECT blocks are
+    // listed in block addrs[NDIRECT].
+
+    // Return the disk block address of the nth block in inode ip.
+    // If there is no such block, bmap allocates one.
+    // returns None if out of disk space.
+    fn bmap(&mut self, bn: u32) -> Option<u32> {
+        let bn = bn as usize;
+        if bn < NDIRECT {
+            if self.addrs[bn] == 0 {
+                self.addrs[bn] = balloc(self.dev)?;
+            }
+            return Some(self.addrs[bn]);
+        }
+
+        let bn = bn - NDIRECT;
+        if bn < NINDIRECT {
+            // Load indirect block, allocating if necessary.
+            if self.addrs[NDIRECT] == 0 {
+                self.addrs[NDIRECT] = balloc(self.dev)?;
+            }
+            let mut bp = bread(self.dev, self.addrs[NDIRECT]);
+            let mut addr = indirect(&bp, bn);
+            if addr == 0 {
+                addr = balloc(self.dev)?;
+                bp.data[bn * 4..bn * 4 + 4].copy_from_slice(&addr.to_ne_bytes());
+                log_write(&bp);
+            }
+            return Some(addr);
+        }
+
+        panic!("bmap: out of range");
+    }
+
+    // Truncate inode (discard contents).
+    // Caller must hold the lock.
+    pub(crate) fn trunc(&mut self) {
+        for i in 0..NDIRECT {
+            if self.addrs[i] != 0 {
+                bfree(self.dev, self.addrs[i]);
+                self.addrs[i] = 0;
+            }
+        }
+
+        if self.addrs[NDIRECT] != 0 {
+            let bp = bread(self.dev, self.addrs[NDIRECT]);
+            for j in 0..NINDIRECT {
+                let addr = indirect(&bp, j);
+                if addr != 0 {
+                    bfree(self.dev, addr);
+                }
+            }
+            drop(bp);
+            bfree(self.dev, self.addrs[NDIRECT]);
+            self.addrs[NDIRECT] = 0;
+        }
+
+        self.size = 0;
+        self.update();
+    }
+
+    // Copy stat information from inode.
+    // Caller must hold the lock.
+    pub(crate) fn stat(&self) -> Stat {
+        Stat {
+            dev: self.dev as i32,
+            ino: self.inum,
+            typ: self.typ,
+            nlink: self.nlink,
+            size: self.size as u64,
+            ..Default::default()
+        }
+    }
+
+    // Read data from inode.
+    // Caller must hold the lock.
+    // copy(chunk, pos) moves each chunk to byte pos of the destination.
+    // Returns the number of bytes read, which is short at the end
+    // of the file, or None if a copy failed.
+    fn readi(
+        &mut self,
+        off: u32,
+        n: usize,
+        mut copy: impl FnMut(&[u8], usize) -> Option<()>,
+    ) -> Option<usize> {
+        let Some(mut n) = u32::try_from(n)
+            .ok()
+            .filter(|&n| off.checked_add(n).is_some())
+        else {
+            return Some(0);
+        };
+        if off > self.size {
+            return Some(0);
+        }
+        if off + n > self.size {
+            n = self.size - off;
+        }
+
+        let mut tot = 0;
+        let mut off = off;
+        while tot < n {
+            let addr = match self.bmap(off / BSIZE as u32) {
+                Some(addr) => addr,
+                None => break,
+            };
+            let bp = bread(self.dev, addr);
+            let start = off as usize % BSIZE;
+            let m = min(n - tot, (BSIZE - start) as u32);
+            copy(&bp.data[start..start + m as usize], tot as usize)?;
+            tot += m;
+            off += m;
+        }
+        Some(tot as usize)
+    }
+
+    // Read into a kernel buffer.
+    pub(crate) fn read(&mut self, dst: &mut [u8], off: u32) -> usize {
+        let n = dst.len();
+        self.readi(off, n, |chunk, pos| {
+            dst[pos..pos + chunk.len()].copy_from_slice(chunk);
+            Some(())
+        })
+        .unwrap_or(0)
+    }
+
+    // Read into a kernel buffer from a 64-bit offset, such as one
+    // taken from an ELF header; used by exec.
+    pub(crate) fn read_at(&mut self, dst: &mut [u8], off: u64) -> Option<usize> {
+        Some(self.read(dst, u32::try_from(off).ok()?))
+    }
+
+    // Read into the current process's memory at user address dst.
+    pub(crate) fn read_user(&mut self, dst: u64, off: u32, n: usize) -> Option<usize> {
+        let pagetable = unsafe { PMASTER.my_proc().context.pagetable };
+        self.readi(off, n, |chunk, pos| {
+            PageTable::copyout(pagetable, dst + pos as u64, chunk).ok()
+        })
+    }
+
+    // Write data to inode.
+    // Caller must hold the lock.
+    // copy(chunk, pos) fills each chunk from byte pos of the source.
+    // Returns the number of bytes successfully written.
+    // If the return value is less than requested,
+    // there was an error of some kind.
+    fn writei(
+        &mut self,
+        off: u32,
+        n: usize,
+        mut copy: impl FnMut(&mut [u8], usize) -> Option<()>,
+    ) -> Option<usize> {
+        let n = u32::try_from(n).ok()?;
+        let end = off.checked_add(n)?;
+        if off > self.size || end as usize > MAXFILE * BSIZE {
+            return None;
+        }
+
+        let mut tot = 0;
+        let mut off = off;
+        while tot < n {
+            let addr = match self.bmap(off / BSIZE as u32) {
+                Some(addr) => addr,
+                None => break,
+            };
+            let mut bp = bread(self.dev, addr);
+            let start = off as usize % BSIZE;
+            let m = min(n - tot, (BSIZE - start) as u32);
+            if copy(&mut bp.data[start..start + m as usize], tot as usize).is_none() {
+                break;
+            }
+            log_write(&bp);
+            tot += m;
+            off += m;
+        }
+
+        if off > self.size {
+            self.size = off;
+        }
+
+        // write the i-node back to disk even if the size didn't change
+        // because the loop above might have called bmap() and added a new
+        // block to self.addrs[].
+        self.update();
+
+        Some(tot as usize)
+    }
+
+    // Write from a kernel buffer.
+    pub(crate) fn write(&mut self, src: &[u8], off: u32) -> Option<usize> {
+        self.writei(off, src.len(), |chunk, pos| {
+            chunk.copy_from_slice(&src[pos..pos + chunk.len()]);
+            Some(())
+        })
+    }
+
+    // Write from the current process's memory at user address src.
+    pub(crate) fn write_user(&mut self, src: u64, off: u32, n: usize) -> Option<usize> {
+        let pagetable = unsafe { PMASTER.my_proc().context.pagetable };
+        self.writei(off, n, |chunk, pos| {
+            PageTable::copyin(pagetable, chunk, src + pos as u64).ok()
+        })
+    }
+}
+
+// block number stored at index i of an indirect block.
+fn indirect(bp: &Buf, i: usize) -> u32 {
+    let mut addr = [0; 4];
+    addr.copy_from_slice(&bp.data[i * 4..i * 4 + 4]);
+    u32::from_ne_bytes(addr)
+}
@@ -0,0 +1,100 @@
+// On-disk file system format.
+// Both the kernel and the host-side mkfs use this file,
+// so it must not depend on anything else in the kernel.
+
+use core::mem::size_of;
+
+pub(crate) const ROOTINO: u32 = 1; // root i-number
+pub(crate) const BSIZE: usize = 1024; // block size
+
+// Disk layout:
+// [ boot block | super block | log | inode blocks |
+//                                          free bit map | data blocks]
+//
+// mkfs computes the super block and builds an initial file system. The
+// super block describes the disk layout:
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub(crate) struct SuperBlock {
+    pub(crate) magic: u32,      // Must be FSMAGIC
+    pub(crate) size: u32,       // Size of file system image (blocks)
+    pub(crate) nblocks: u32,    // Number of data blocks
+    pub(crate) ninodes: u32,    // Number of inodes.
+    pub(crate) nlog: u32,       // Number of log blocks
+    pub(crate) logstart: u32,   // Block number of first log block
+    pub(crate) inodestart: u32, // Block number of first inode block
+    pub(crate) bmapstart: u32,  // Block number of first free map block
+}
+
+pub(crate) const FSMAGIC: u32 = 0x10203040;
+
+pub(crate) const NDIRECT: usize = 12;
+pub(crate) const NINDIRECT: usize = BSIZE / size_of::<u32>();
+pub(crate) const MAXFILE: usize = NDIRECT + NINDIRECT;
+
+// File types
+pub(crate) const T_DIR: i16 = 1; // Directory
+pub(crate) const T_FILE: i16 = 2; // File
+pub(crate) const T_DEVICE: i16 = 3; // Device
+
+// On-disk inode structure
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Dinode {
+    pub(crate) typ: i16,                  // File type
+    pub(crate) major: i16,                // Major device number (T_DEVICE only)
+    pub(crate) minor: i16,                // Minor device number (T_DEVICE only)
+    pub(crate) nlink: i16,                // Number of links to inode in file system
+    pub(crate) size: u32,                 // Size of file (bytes)
+    pub(crate) addrs: [u32; NDIRECT + 1], // Data block addresses
+}
+
+// Inodes per block.
+pub(crate) const IPB: u32 = (BSIZE / size_of::<Dinode>()) as u32;
+
+// Block containing inode i
+pub(crate) const fn iblock(i: u32, sb: &SuperBlock) -> u32 {
+    i / IPB + sb.inodestart
+}
+
+// Bitmap bits per block
+pub(crate) const BPB: u32 = (BSIZE * 8) as u32;
+
+// Block of free map containing bit for block b
+pub(crate) const fn bblock(b: u32, sb: &SuperBlock) -> u32 {
+    b / BPB + sb.bmapstart
+}
+
+// Directory is a file containing a sequence of dirent structures.
+pub(crate) const DIRSIZ: usize = 14;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Dirent {
+    pub(crate) inum: u16,
+    pub(crate) name: [u8; DIRSIZ],
+}
+
+impl Dirent {
+    // the name up to its nul terminator, if any.
+    pub(crate) fn name(&self) -> &[u8] {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(DIRSIZ);
+        &self.name[..len]
+    }
+
+    // store name, truncated to DIRSIZ and nul-padded.
+    pub(crate) fn set_name(&mut self, name: &[u8]) {
+        let len = name.len().min(DIRSIZ);
+        self.name = [0; DIRSIZ];
+        self.name[..len].copy_from_slice(&name[..len]);
+    }
+}
+
+// view an on-disk structure as raw bytes.
+pub(crate) fn as_bytes<T: Copy>(v: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(v as *const T as *const u8, size_of::<T>()) }
+}
+
+pub(crate) fn as_bytes_mut<T: Copy>(v: &mut T) -> &mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(v as *mut T as *mut u8, size_of::<T>()) }
+}
@@ -0,0 +1,233 @@
+// Simple logging that allows concurrent FS system calls.
+//
+// A log transaction contains the updates of multiple FS system
+// calls. The logging system only commits when there are
+// no FS system calls active. Thus there is never
+// any reasoning required about whether a commit might
+// write an uncommitted system call's updates to disk.
+//
+// A system call should call begin_op()/end_op() to mark
+// its start and end. Usually begin_op() just increments
+// the count of in-progress FS system calls and returns.
+// But if it thinks the log is close to running out, it
+// sleeps until the last outstanding end_op() commits.
+//
+// The log is a physical re-do log containing disk blocks.
+// The on-disk log format:
+//   header block, containing block #s for block A, B, C, ...
+//   block A
+//   block B
+//   block C
+//   ...
+// Log appends are synchronous.
+
+use core::mem::size_of;
+
+use super::bio::{bpin, bread, bunpin, bwrite, BufGuard};
+use super::layout::{as_bytes, as_bytes_mut, SuperBlock, BSIZE};
+use crate::arch::{LOGSIZE, MAXOPBLOCKS};
+use crate::lock::spinlock::SpinLock;
+use crate::process::master::PMASTER;
+
+// Contents of the header block, used for both the on-disk header block
+// and to keep track in memory of logged block# before commit.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LogHeader {
+    n: u32,
+    block: [u32; LOGSIZE],
+}
+
+struct Log {
+    start: u32,
+    size: u32,
+    outstanding: usize, // how many FS sys calls are executing.
+    committing: bool,   // in commit(), please wait.
+    dev: u32,
+    lh: LogHeader,
+}
+
+static LOG: SpinLock<Log> = SpinLock::new(Log {
+    start: 0,
+    size: 0,
+    outstanding: 0,
+    committing: false,
+    dev: 0,
+    lh: LogHeader {
+        n: 0,
+        block: [0; LOGSIZE],
+    },
+});
+
+// sleep here to wait for the log to commit or to make room.
+fn log_chan() -> usize {
+    &LOG as *const SpinLock<Log> as usize
+}
+
+pub(crate) fn initlog(dev: u32, sb: &SuperBlock) {
+    if size_of::<LogHeader>() >= BSIZE {
+        panic!("initlog: too big logheader");
+    }
+
+    let mut log = LOG.lock();
+    log.start = sb.logstart;
+    log.size = sb.nlog;
+    log.dev = dev;
+    drop(log);
+    recover_from_log();
+}
+
+// Copy committed blocks from log to their home location
+fn install_trans(lh: &LogHeader, recovering: bool) {
+    let (dev, start) = {
+        let log = LOG.lock();
+        (log.dev, log.start)
+    };
+    for tail in 0..lh.n {
+        let lbuf = bread(dev, start + tail + 1); // read log block
+        let mut dbuf = bread(dev, lh.block[tail as usize]); // read dst
+        dbuf.data.copy_from_slice(&lbuf.data); // copy block to dst
+        bwrite(&mut dbuf); // write dst to disk
+        if !recovering {
+            bunpin(&dbuf);
+        }
+    }
+}
+
+// Read the log header from disk into the in-memory log header
+fn read_head() -> LogHeader {
+    let (dev, start) = {
+        let log = LOG.lock();
+        (log.dev, log.start)
+    };
+    let buf = bread(dev, start);
+    let mut lh = LogHeader {
+        n: 0,
+        block: [0; LOGSIZE],
+    };
+    as_bytes_mut(&mut lh).copy_from_slice(&buf.data[..size_of::<LogHeader>()]);
+    lh
+}
+
+// Write in-memory log header to disk.
+// This is the true point at which the
+// current transaction commits.
+fn write_head(lh: &LogHeader) {
+    let (dev, start) = {
+        let log = LOG.lock();
+        (log.dev, log.start)
+    };
+    let mut buf = bread(dev, start);
+    buf.data[..size_of::<LogHeader>()].copy_from_slice(as_bytes(lh));
+    bwrite(&mut buf);
+}
+
+fn recover_from_log() {
+    let mut lh = read_head();
+    install_trans(&lh, true); // if committed, copy from log to disk
+    lh.n = 0;
+    write_head(&lh); // clear the log
+    LOG.lock().lh = lh;
+}
+
+// called at the start of each FS system call.
+pub(crate) fn begin_op() {
+    let mut log = LOG.lock();
+    loop {
+        if log.committing {
+            log = unsafe { PMASTER.sleep(log_chan(), log) };
+        } else if log.lh.n as usize + (log.outstanding + 1) * MAXOPBLOCKS > LOGSIZE {
+            // this op might exhaust log space; wait for commit.
+            log = unsafe { PMASTER.sleep(log_chan(), log) };
+        } else {
+            log.outstanding += 1;
+            break;
+        }
+    }
+}
+
+// called at the end of each FS system call.
+// commits if this was the last outstanding operation.
+pub(crate) fn end_op() {
+    let mut log = LOG.lock();
+    log.outstanding -= 1;
+    if log.committing {
+        panic!("log.committing");
+    }
+    let do_commit = log.outstanding == 0;
+    if do_commit {
+        log.committing = true;
+    } else {
+        // begin_op() may be waiting for log space,
+        // and decrementing log.outstanding has decreased
+        // the amount of reserved space.
+        unsafe { PMASTER.wakeup(log_chan()) };
+    }
+    drop(log);
+
+    if do_commit {
+        // call commit w/o holding locks, since not allowed
+        // to sleep with locks.
+        commit();
+        let mut log = LOG.lock();
+        log.committing = false;
+        unsafe { PMASTER.wakeup(log_chan()) };
+    }
+}
+
+// Copy modified blocks from cache to log.
+fn write_log(lh: &LogHeader) {
+    let (dev, start) = {
+        let log = LOG.lock();
+        (log.dev, log.start)
+    };
+    for tail in 0..lh.n {
+        let mut to = bread(dev, start + tail + 1); // log block
+        let from = bread(dev, lh.block[tail as usize]); // cache block
+        to.data.copy_from_slice(&from.data);
+        bwrite(&mut to); // write the log
+    }
+}
+
+fn commit() {
+    // no FS system call is running while committing is set,
+    // so the header can't change under us.
+    let mut lh = LOG.lock().lh;
+    if lh.n > 0 {
+        write_log(&lh); // Write modified blocks from cache to log
+        write_head(&lh); // Write header to disk -- the real commit
+        install_trans(&lh, false); // Now install writes to home locations
+        lh.n = 0;
+        write_head(&lh); // Erase the transaction from the log
+        LOG.lock().lh = lh;
+    }
+}
+
+// Caller has modified b.data and is done with the buffer.
+// Record the block number and pin in the cache by increasing refcnt.
+// commit()/write_log() will do the disk write.
+//
+// log_write() replaces bwrite(); a typical use is:
+//   bp = bread(...)
+//   modify bp.data[]
+//   log_write(&bp)
+//   drop(bp)
+pub(crate) fn log_write(b: &BufGuard) {
+    let mut log = LOG.lock();
+    if log.lh.n as usize >= LOGSIZE || log.lh.n + 1 >= log.size {
+        panic!("too big a transaction");
+    }
+    if log.outstanding < 1 {
+        panic!("log_write outside of trans");
+    }
+
+    let n = log.lh.n as usize;
+    let blockno = b.blockno();
+    // log absorption
+    if !log.lh.block[..n].contains(&blockno) {
+        // Add new block to log
+        log.lh.block[n] = blockno;
+        bpin(b);
+        log.lh.n += 1;
+    }
+}
@@ -0,0 +1,102 @@
+// File system implementation.  Five layers:
+//   + Blocks: allocator for raw disk blocks.
+//   + Files: inode allocator, reading, writing, metadata.
+//   + Directories: inode with special contents (list of other inodes!)
+//   + Names: paths like /usr/rtm/xv6/fs.c for convenient naming.
+//
+// This file contains the low-level block allocation routines;
+// inode.rs and dir.rs hold the higher layers, bio.rs the block cache.
+
+use core::mem::size_of;
+
+use crate::lock::spinlock::SpinLock;
+use crate::{print, println};
+
+use self::bio::bread;
+use self::layout::{as_bytes_mut, bblock, SuperBlock, BPB, FSMAGIC};
+use self::log::{initlog, log_write};
+
+pub(crate) mod bio;
+pub(crate) mod dir;
+pub(crate) mod file;
+pub(crate) mod inode;
+pub(crate) mod layout;
+pub(crate) mod log;
+pub(crate) mod pipe;
+
+// there should be one superblock per disk device, but we run with
+// only one device
+static SB: SpinLock<Option<SuperBlock>> = SpinLock::new(None);
+
+pub(crate) fn sb() -> SuperBlock {
+    SB.lock()
+        .unwrap_or_else(|| panic!("file system not initialized"))
+}
+
+// Read the super block.
+fn readsb(dev: u32) -> SuperBlock {
+    let bp = bread(dev, 1);
+    let mut sb = SuperBlock::default();
+    as_bytes_mut(&mut sb).copy_from_slice(&bp.data[..size_of::<SuperBlock>()]);
+    sb
+}
+
+// Init fs
+pub(crate) fn fsinit(dev: u32) {
+    let sb = readsb(dev);
+    if sb.magic != FSMAGIC {
+        panic!("invalid file system");
+    }
+    *SB.lock() = Some(sb);
+    initlog(dev, &sb);
+}
+
+// Zero a block.
+fn bzero(dev: u32, bno: u32) {
+    let mut bp = bread(dev, bno);
+    bp.data.fill(0);
+    log_write(&bp);
+}
+
+// Blocks.
+
+// Allocate a zeroed disk block.
+// returns None if out of disk space.
+pub(crate) fn balloc(dev: u32) -> Option<u32> {
+    let sb = sb();
+    let mut b = 0;
+    while b < sb.size {
+        let mut bp = bread(dev, bblock(b, &sb));
+        let mut bi = 0;
+        while bi < BPB && b + bi < sb.size {
+            let m = 1 << (bi % 8);
+            let byte = &mut bp.data[(bi / 8) as usize];
+            if *byte & m == 0 {
+                // Is block free?
+                *byte |= m; // Mark block in use.
+                log_write(&bp);
+                drop(bp);
+                bzero(dev, b + bi);
+                return Some(b + bi);
+            }
+            bi += 1;
+        }
+        b += BPB;
+    }
+    println!("balloc: out of blocks");
+    None
+}
+
+// Free a disk block.
+pub(crate) fn bfree(dev: u32, b: u32) {
+    let sb = sb();
+    let mut bp = bread(dev, bblock(b, &sb));
+    let bi = b % BPB;
+    let m = 1 << (bi % 8);
+    let byte = &mut bp.data[(bi / 8) as usize];
+    if *byte & m == 0 {
+        panic!("freeing free block");
+    }
+    *byte &= !m;
+    log_write(&bp);
+}
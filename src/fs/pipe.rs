@@ -0,0 +1,143 @@
+// Pipes.
+//
+// A pipe is a circular buffer in a page of its own from KALLOC,
+// shared by a read-only and a write-only open file. The page is
+// freed once both ends have been closed.
+
+use core::ptr;
+
+use crate::lock::spinlock::{Guard, SpinLock};
+use crate::memory::kalloc::KALLOC;
+use crate::memory::vm::PageTable;
+use crate::process::master::PMASTER;
+use crate::syscall::Errno;
+
+const PIPESIZE: usize = 512;
+
+struct PipeInner {
+    data: [u8; PIPESIZE],
+    nread: usize,    // number of bytes read
+    nwrite: usize,   // number of bytes written
+    readopen: bool,  // read fd is still open
+    writeopen: bool, // write fd is still open
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct Pipe {
+    ptr: *mut SpinLock<PipeInner>,
+}
+
+unsafe impl Send for Pipe {}
+
+impl Pipe {
+    #[cfg_attr(feature = "kalloc-debug", track_caller)]
+    pub(crate) fn alloc() -> Option<Pipe> {
+        let page = KALLOC.alloc()?;
+        let ptr = page as *mut SpinLock<PipeInner>;
+        unsafe {
+            ptr::write(
+                ptr,
+                SpinLock::new(PipeInner {
+                    data: [0; PIPESIZE],
+                    nread: 0,
+                    nwrite: 0,
+                    readopen: true,
+                    writeopen: true,
+                }),
+            );
+        }
+        Some(Pipe { ptr })
+    }
+
+    fn lock(&self) -> Guard<'_, PipeInner> {
+        unsafe { (*self.ptr).lock() }
+    }
+
+    // readers sleep on the pipe's address, writers one byte past it.
+    fn read_chan(&self) -> usize {
+        self.ptr as usize
+    }
+
+    fn write_chan(&self) -> usize {
+        self.ptr as usize + 1
+    }
+
+    // close one end of the pipe, freeing it when both are closed.
+    pub(crate) fn close(self, writable: bool) {
+        let mut pi = self.lock();
+        if writable {
+            pi.writeopen = false;
+            unsafe { PMASTER.wakeup(self.read_chan()) };
+        } else {
+            pi.readopen = false;
+            unsafe { PMASTER.wakeup(self.write_chan()) };
+        }
+        let free = !pi.readopen && !pi.writeopen;
+        drop(pi);
+        if free {
+            KALLOC.free(self.ptr as u64);
+        }
+    }
+
+    // write n bytes from user address addr,
+    // sleeping while the pipe is full.
+    pub(crate) fn write(&self, addr: u64, n: usize) -> Result<usize, Errno> {
+        let pagetable = unsafe { PMASTER.my_proc().context.pagetable };
+        let mut pi = self.lock();
+        let mut i = 0;
+        while i < n {
+            if !pi.readopen {
+                return Err(Errno::EPIPE);
+            }
+            if unsafe { PMASTER.killed() } {
+                return Err(Errno::EINTR);
+            }
+            if pi.nwrite == pi.nread + PIPESIZE {
+                // pipewrite-full
+                unsafe { PMASTER.wakeup(self.read_chan()) };
+                pi = unsafe { PMASTER.sleep(self.write_chan(), pi) };
+            } else {
+                let mut ch = [0];
+                if PageTable::copyin(pagetable, &mut ch, addr + i as u64).is_err() {
+                    break;
+                }
+                let w = pi.nwrite % PIPESIZE;
+                pi.data[w] = ch[0];
+                pi.nwrite += 1;
+                i += 1;
+            }
+        }
+        unsafe { PMASTER.wakeup(self.read_chan()) };
+        Ok(i)
+    }
+
+    // read up to n bytes to user address addr,
+    // sleeping while the pipe is empty and a writer remains.
+    // returns 0 at end of file.
+    pub(crate) fn read(&self, addr: u64, n: usize) -> Result<usize, Errno> {
+        let pagetable = unsafe { PMASTER.my_proc().context.pagetable };
+        let mut pi = self.lock();
+        while pi.nread == pi.nwrite && pi.writeopen {
+            if unsafe { PMASTER.killed() } {
+                return Err(Errno::EINTR);
+            }
+            // pipe-empty
+            pi = unsafe { PMASTER.sleep(self.read_chan(), pi) };
+        }
+        let mut i = 0;
+        while i < n {
+            // piperead-copy
+            if pi.nread == pi.nwrite {
+                break;
+            }
+            let ch = pi.data[pi.nread % PIPESIZE];
+            pi.nread += 1;
+            if PageTable::copyout(pagetable, addr + i as u64, &[ch]).is_err() {
+                break;
+            }
+            i += 1;
+        }
+        unsafe { PMASTER.wakeup(self.write_chan()) };
+        Ok(i)
+    }
+}
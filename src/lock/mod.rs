@@ -1 +1,2 @@
+pub(crate) mod sleeplock;
 pub(crate) mod spinlock;
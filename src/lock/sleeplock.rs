@@ -0,0 +1,80 @@
+use crate::lock::spinlock::SpinLock;
+use crate::process::master::PMASTER;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut, Drop};
+
+// Long-term locks for processes.
+// A process that finds the lock held sleeps instead of spinning,
+// so it may be held across disk I/O and other sleeps.
+pub(crate) struct SleepLock<T> {
+    inner: SpinLock<SleepState>, // spinlock protecting this sleep lock
+    data: UnsafeCell<T>,
+}
+
+struct SleepState {
+    locked: bool, // Is the lock held?
+    pid: usize,   // Process holding lock
+}
+
+unsafe impl<T> Sync for SleepLock<T> where T: Send {}
+
+impl<T> SleepLock<T> {
+    pub(crate) const fn new(data: T) -> Self {
+        Self {
+            inner: SpinLock::new(SleepState {
+                locked: false,
+                pid: 0,
+            }),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> SleepGuard<'_, T> {
+        let mut state = self.inner.lock();
+        while state.locked {
+            state = unsafe { PMASTER.sleep(self.chan(), state) };
+        }
+        state.locked = true;
+        state.pid = unsafe { PMASTER.my_proc().context.pid };
+        SleepGuard { lock: self }
+    }
+
+    // whether the current process holds the lock.
+    #[allow(dead_code)]
+    pub(crate) fn holding(&self) -> bool {
+        let state = self.inner.lock();
+        state.locked && state.pid == unsafe { PMASTER.my_proc().context.pid }
+    }
+
+    fn chan(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+pub struct SleepGuard<'a, T> {
+    lock: &'a SleepLock<T>,
+}
+
+impl<T> Deref for SleepGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SleepGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SleepGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.inner.lock();
+        state.locked = false;
+        state.pid = 0;
+        unsafe {
+            PMASTER.wakeup(self.lock.chan());
+        }
+    }
+}
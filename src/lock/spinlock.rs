@@ -43,6 +43,16 @@ pub struct Guard<'a, T> {
     lock: &'a SpinLock<T>,
 }
 
+impl<'a, T> Guard<'a, T> {
+    // release the lock early, handing back the SpinLock
+    // so that the caller can acquire it again later.
+    pub(crate) fn release(self) -> &'a SpinLock<T> {
+        let lock = self.lock;
+        drop(self);
+        lock
+    }
+}
+
 impl<T> Deref for Guard<'_, T> {
     type Target = T;
     fn deref(&self) -> &T {
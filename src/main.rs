@@ -7,6 +7,8 @@
 mod arch;
 mod boot;
 mod driver;
+mod exec;
+mod fs;
 mod lock;
 mod memory;
 mod process;
@@ -30,6 +32,7 @@ use process::master::PMASTER;
 //====================================
 #[panic_handler]
 fn panic(panic: &PanicInfo<'_>) -> ! {
+    driver::uart::PANICKED.store(true, SeqCst);
     println!("{}", panic);
     loop {}
 }
@@ -43,6 +46,8 @@ pub(crate) fn kmain() {
         println!("RXV6: An Eduacationol OS In Rust.");
         println!("{}", LOGO);
         Kalloc::init_kernel_page_allocator(); // init the kernel page allocator.
+        #[cfg(feature = "kalloc-debug")]
+        Kalloc::test(); // check the allocator on a pool of its own.
         Kvm::init_kernel_page_table(); // create the kernel page table.
         Kvm::init_hart(); // turn on the kernel page table.
         heap::init_kernel_heap(); // init the kernel heap
@@ -51,6 +56,7 @@ pub(crate) fn kmain() {
         trap::plic::init(); // set up interrupt controller
         trap::plic::init_hart(); // ask PLIC for device interrupts
         println!("Loading Kernel Trap and PLIC...");
+        driver::virtio_disk::init(); // emulated hard disk
         process::init(); // process table
         process::user_init(); // first user process
         println!("Entering Userland...");
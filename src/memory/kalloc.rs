@@ -1,72 +1,395 @@
+#[cfg(feature = "kalloc-debug")]
+use core::panic::Location;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+#[cfg(feature = "kalloc-debug")]
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64},
+};
+
+use crate::arch::{cpu_id, NCPU};
 use crate::lock::spinlock::SpinLock;
+use crate::{print, println};
 
-use super::layout::{END, PHYSTOP};
+use super::layout::{END, KERNBASE, PHYSTOP};
 
 const PGSIZE: usize = 4096;
+const NPAGE: usize = (PHYSTOP - KERNBASE) as usize / PGSIZE; // physical pages the kernel manages
+const NSTEAL: usize = 32; // pages taken from another cpu's list at once
+
+// With the kalloc-debug feature, freed pages are filled with
+// JUNK_FREE and newly allocated ones with JUNK_ALLOC, to catch
+// dangling references and callers that forget to clear a page.
+#[cfg(feature = "kalloc-debug")]
+const JUNK_FREE: u8 = 1;
+#[cfg(feature = "kalloc-debug")]
+const JUNK_ALLOC: u8 = 5;
 
-pub(crate) static KALLOC: SpinLock<Kalloc> = SpinLock::new(Kalloc::new());
+pub(crate) static KALLOC: Kalloc = Kalloc::new();
 
 /// Kernel Page allocator
+// Each cpu allocates from and frees to a list of its own,
+// so harts do not serialize on a single lock; a cpu whose list
+// runs dry steals a batch of pages from another.
 pub struct Kalloc {
+    cpus: [SpinLock<FreeList>; NCPU],
+    refcnt: [AtomicU8; NPAGE], // number of page tables (or other users) holding each page
+    npage: AtomicUsize,        // pages handed to the allocator by insert()
+    #[cfg(feature = "kalloc-debug")]
+    tags: [AtomicPtr<Location<'static>>; NPAGE], // who allocated each page
+    #[cfg(feature = "kalloc-debug")]
+    lo: AtomicU64, // lowest page handed to insert()
+    #[cfg(feature = "kalloc-debug")]
+    hi: AtomicU64, // end of the highest range handed to insert()
+}
+
+struct FreeList {
     head: Option<*mut Page>,
+    len: usize, // pages on the list
+    stats: KallocStats,
 }
 
-unsafe impl Send for Kalloc {}
+unsafe impl Send for FreeList {}
+
+// Per-cpu allocator counters.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KallocStats {
+    pub allocs: u64, // pages allocated by this cpu
+    pub frees: u64,  // pages freed onto this cpu's list
+    pub steals: u64, // times this cpu had to take pages from another
+    pub stolen: u64, // pages taken from this cpu's list by others
+}
 
 #[repr(C, align(4096))]
 pub struct Page {
     next: Option<*mut Page>,
 }
 
+impl FreeList {
+    const fn new() -> Self {
+        Self {
+            head: None,
+            len: 0,
+            stats: KallocStats {
+                allocs: 0,
+                frees: 0,
+                steals: 0,
+                stolen: 0,
+            },
+        }
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        let ptr = self.head?;
+        let page = unsafe { ptr.as_mut().unwrap() };
+        let _ = core::mem::replace(&mut self.head, page.next);
+        self.len -= 1;
+        Some(ptr as u64)
+    }
+
+    // append to free list
+    fn push(&mut self, addr: u64) {
+        let ptr = addr as *mut Page;
+        let head = &mut self.head;
+        let next = core::mem::replace(head, Some(ptr));
+        unsafe {
+            ptr.as_mut().unwrap().next = next;
+        }
+        self.len += 1;
+    }
+}
+
 impl Kalloc {
     pub const fn new() -> Self {
-        Self { head: None }
+        Self {
+            cpus: [const { SpinLock::new(FreeList::new()) }; NCPU],
+            refcnt: [const { AtomicU8::new(0) }; NPAGE],
+            npage: AtomicUsize::new(0),
+            #[cfg(feature = "kalloc-debug")]
+            tags: [const { AtomicPtr::new(ptr::null_mut()) }; NPAGE],
+            #[cfg(feature = "kalloc-debug")]
+            lo: AtomicU64::new(u64::MAX),
+            #[cfg(feature = "kalloc-debug")]
+            hi: AtomicU64::new(0),
+        }
     }
 
     // init the kernel page allocator
     pub fn init_kernel_page_allocator() {
         unsafe {
-            KALLOC.lock().insert(END, PHYSTOP);
+            KALLOC.insert(END, PHYSTOP);
         }
     }
 
-    // free a allocated page
-    pub fn insert(&mut self, start: u64, end: u64) {
+    // hand the pages in [start, end) to the allocator.
+    // they all go to this cpu; the others steal what they need.
+    pub fn insert(&self, start: u64, end: u64) {
+        self.insert_on(cpu_id(), start, end);
+    }
+
+    // hand the pages in [start, end) to cpu id's list.
+    fn insert_on(&self, id: usize, start: u64, end: u64) {
+        #[cfg(feature = "kalloc-debug")]
+        {
+            self.lo.fetch_min(start, Ordering::Relaxed);
+            self.hi.fetch_max(end, Ordering::Relaxed);
+        }
+        let mut list = self.cpus[id].lock();
         let mut page = start;
         while page < end {
-            self.free(page);
+            #[cfg(feature = "kalloc-debug")]
+            Self::fill(page, JUNK_FREE);
+            list.push(page);
+            self.npage.fetch_add(1, Ordering::Relaxed);
             page += PGSIZE as u64;
         }
     }
 
-    // allocate a new page
+    // allocate a new page, with a reference count of 1
     // caller should be responsible for clearing the page
-    pub fn alloc(&mut self) -> Option<u64> {
-        let ptr = self.head?;
-        let page = unsafe { ptr.as_mut().unwrap() };
-        let _ = core::mem::replace(&mut self.head, page.next);
-        Some(ptr as u64)
+    // with kalloc-debug, the page is tagged with the caller's location;
+    // wrappers that allocate on someone's behalf (create_table, uvmalloc,
+    // TrapFrame::new, ...) are track_caller too, so the tag names their caller.
+    #[cfg_attr(feature = "kalloc-debug", track_caller)]
+    pub fn alloc(&self) -> Option<u64> {
+        // being moved to another cpu after reading cpu_id()
+        // is harmless: the list is still locked while we use it.
+        let id = cpu_id();
+        let page = {
+            let mut list = self.cpus[id].lock();
+            let page = list.pop();
+            if page.is_some() {
+                list.stats.allocs += 1;
+            }
+            page
+        };
+        let page = page.or_else(|| self.steal(id))?;
+        self.refcnt[Self::index(page)].store(1, Ordering::Release);
+        #[cfg(feature = "kalloc-debug")]
+        {
+            Self::fill(page, JUNK_ALLOC);
+            let caller = Location::caller() as *const Location<'static>;
+            self.tags[Self::index(page)].store(caller as *mut _, Ordering::Relaxed);
+        }
+        Some(page)
     }
 
-    // append to free list
-    pub fn free(&mut self, addr: u64) {
-        let ptr = addr as *mut Page;
-        let head = &mut self.head;
-        let next = core::mem::replace(head, Some(ptr));
+    // take a batch of pages from the first other cpu that has any,
+    // keep one and put the rest on cpu id's list.
+    // only one list is locked at a time, so two cpus stealing
+    // from each other cannot deadlock.
+    fn steal(&self, id: usize) -> Option<u64> {
+        for victim in (1..NCPU).map(|i| (id + i) % NCPU) {
+            let mut batch = FreeList::new();
+            let mut n = 0;
+            {
+                let mut list = self.cpus[victim].lock();
+                while n < NSTEAL {
+                    let Some(page) = list.pop() else {
+                        break;
+                    };
+                    batch.push(page);
+                    n += 1;
+                }
+                list.stats.stolen += n as u64;
+            }
+            if n == 0 {
+                continue;
+            }
+
+            let page = batch.pop();
+            let mut list = self.cpus[id].lock();
+            while let Some(rest) = batch.pop() {
+                list.push(rest);
+            }
+            list.stats.allocs += 1;
+            list.stats.steals += 1;
+            return page;
+        }
+        None
+    }
+
+    // take another reference to an allocated page,
+    // e.g. when a copy-on-write fork shares it.
+    pub fn incref(&self, addr: u64) {
+        let cnt = &self.refcnt[Self::index(addr)];
+        let old = cnt
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |c| c.checked_add(1))
+            .unwrap_or_else(|_| panic!("incref: overflow"));
+        assert!(old > 0, "incref: free page");
+    }
+
+    // the number of references to an allocated page.
+    pub fn refcnt(&self, addr: u64) -> usize {
+        self.refcnt[Self::index(addr)].load(Ordering::Acquire) as usize
+    }
+
+    // drop a reference to a page, putting it back on
+    // this cpu's free list when none are left.
+    // return the number of references that remain.
+    pub fn decref(&self, addr: u64) -> usize {
+        #[cfg(feature = "kalloc-debug")]
+        self.check(addr);
+        let cnt = &self.refcnt[Self::index(addr)];
+        let left = match cnt.fetch_update(Ordering::AcqRel, Ordering::Acquire, |c| c.checked_sub(1))
+        {
+            Ok(old) => old as usize - 1,
+            Err(_) => panic!("kfree: double free of page {:#x}", addr),
+        };
+        if left == 0 {
+            #[cfg(feature = "kalloc-debug")]
+            {
+                self.tags[Self::index(addr)].store(ptr::null_mut(), Ordering::Relaxed);
+                Self::fill(addr, JUNK_FREE);
+            }
+            let mut list = self.cpus[cpu_id()].lock();
+            list.push(addr);
+            list.stats.frees += 1;
+        }
+        left
+    }
+
+    // free a page from alloc(), dropping the caller's reference.
+    pub fn free(&self, addr: u64) {
+        self.decref(addr);
+    }
+
+    // a snapshot of each cpu's counters.
+    pub fn stats(&self) -> [KallocStats; NCPU] {
+        let mut stats = [KallocStats::default(); NCPU];
+        for (s, list) in stats.iter_mut().zip(self.cpus.iter()) {
+            *s = list.lock().stats;
+        }
+        stats
+    }
+
+    // the number of free and allocated pages.
+    pub fn counts(&self) -> (usize, usize) {
+        let free: usize = self.cpus.iter().map(|list| list.lock().len).sum();
+        (free, self.npage.load(Ordering::Relaxed) - free)
+    }
+
+    // Print page usage to the console. For debugging.
+    // Runs when user types ^K on console.
+    // With kalloc-debug, also list every page still allocated
+    // and where it was allocated from.
+    pub fn dump(&self) {
+        let (free, used) = self.counts();
+        println!();
+        println!("kalloc: {} pages free, {} in use", free, used);
+        for (id, s) in self.stats().iter().enumerate() {
+            println!(
+                "cpu {}: allocs {} frees {} steals {} stolen {}",
+                id, s.allocs, s.frees, s.steals, s.stolen
+            );
+        }
+
+        #[cfg(feature = "kalloc-debug")]
+        for (i, tag) in self.tags.iter().enumerate() {
+            let refcnt = self.refcnt[i].load(Ordering::Acquire);
+            if refcnt == 0 {
+                continue;
+            }
+            let page = KERNBASE + (i * PGSIZE) as u64;
+            match unsafe { tag.load(Ordering::Relaxed).as_ref() } {
+                Some(caller) => println!("{:#x} ref {} {}", page, refcnt, caller),
+                None => println!("{:#x} ref {} ?", page, refcnt),
+            }
+        }
+    }
+
+    // panic unless addr is a page the allocator could have handed out,
+    // i.e. an aligned page inside the ranges given to insert().
+    #[cfg(feature = "kalloc-debug")]
+    fn check(&self, addr: u64) {
+        let lo = self.lo.load(Ordering::Relaxed);
+        let hi = self.hi.load(Ordering::Relaxed);
+        if addr & (PGSIZE as u64 - 1) != 0 || !(lo..hi).contains(&addr) {
+            panic!("kfree: bad page {:#x}", addr);
+        }
+    }
+
+    #[cfg(feature = "kalloc-debug")]
+    fn fill(page: u64, junk: u8) {
         unsafe {
-            ptr.as_mut().unwrap().next = next;
+            ptr::write_bytes(page as *mut u8, junk, PGSIZE);
         }
     }
 
-    // mock test for page allocator
-    pub fn _test() {
-        static mut POOL: [u8; PGSIZE * 2] = [0; PGSIZE * 2];
-        let start = unsafe { POOL.as_mut_ptr() as u64 };
+    // index into refcnt of the page at physical address addr:
+    // its physical page number, counted from KERNBASE.
+    fn index(addr: u64) -> usize {
+        assert!(
+            (KERNBASE..PHYSTOP).contains(&addr),
+            "kalloc: bad physical address {:#x}",
+            addr
+        );
+        ((addr >> 12) - (KERNBASE >> 12)) as usize
+    }
+
+    // self-test for the page allocator, on a pool of its own.
+    // kmain runs it at boot when built with kalloc-debug.
+    #[cfg(feature = "kalloc-debug")]
+    pub fn test() {
+        const NPOOL: usize = NSTEAL + 8;
+        #[repr(C, align(4096))]
+        struct Pool([u8; PGSIZE * NPOOL]);
+        static mut POOL: Pool = Pool([0; PGSIZE * NPOOL]);
+        static TEST: Kalloc = Kalloc::new();
+        let pool = unsafe { POOL.0.as_mut_ptr() as u64 };
+        let start = pool;
         let end = start + (PGSIZE as u64) * 2;
-        let mut kalloc = Kalloc::new();
+        let kalloc = &TEST;
         kalloc.insert(start, end);
+        assert_eq!(kalloc.counts(), (2, 0));
         assert_eq!(kalloc.alloc().unwrap(), start + PGSIZE as u64);
         assert_eq!(kalloc.alloc().unwrap(), start);
         assert_eq!(kalloc.alloc(), None);
+        assert_eq!(kalloc.counts(), (0, 2));
+        kalloc.incref(start);
+        assert_eq!(kalloc.decref(start), 1);
+        assert_eq!(kalloc.counts(), (0, 2));
+        assert_eq!(kalloc.decref(start), 0);
+        assert_eq!(kalloc.counts(), (1, 1));
+        // a free page is junk past the list link.
+        {
+            let link = core::mem::size_of::<Option<*mut Page>>();
+            let page = unsafe { core::slice::from_raw_parts(start as *const u8, PGSIZE) };
+            assert!(page[link..].iter().all(|&b| b == JUNK_FREE));
+        }
+        assert_eq!(kalloc.alloc().unwrap(), start);
+        {
+            let page = unsafe { core::slice::from_raw_parts(start as *const u8, PGSIZE) };
+            assert!(page.iter().all(|&b| b == JUNK_ALLOC));
+        }
+        assert_eq!(kalloc.counts(), (0, 2));
+
+        // this cpu's list is empty: alloc steals a batch
+        // from the next cpu that has pages.
+        let id = cpu_id();
+        let victim = (id + 1) % NCPU;
+        kalloc.insert_on(victim, end, pool + (PGSIZE * NPOOL) as u64);
+        let page = kalloc.alloc().unwrap();
+        assert!((end..pool + (PGSIZE * NPOOL) as u64).contains(&page));
+        let stats = kalloc.stats();
+        assert_eq!(stats[id].allocs, 4);
+        assert_eq!(stats[id].steals, 1);
+        assert_eq!(stats[victim].stolen, NSTEAL as u64);
+        assert_eq!(stats[victim].allocs, 0);
+        assert_eq!(kalloc.cpus[id].lock().len, NSTEAL - 1);
+        assert_eq!(kalloc.cpus[victim].lock().len, NPOOL - 2 - NSTEAL);
+        assert_eq!(kalloc.counts(), (NPOOL - 3, 3));
+
+        // the rest of the batch is now on this cpu's list,
+        // so the next NSTEAL-1 allocations don't steal again.
+        for _ in 1..NSTEAL {
+            assert!(kalloc.alloc().is_some());
+        }
+        let stats = kalloc.stats();
+        assert_eq!(stats[id].allocs, 3 + NSTEAL as u64);
+        assert_eq!(stats[id].steals, 1);
+        assert_eq!(stats[victim].stolen, NSTEAL as u64);
+        assert_eq!(kalloc.cpus[id].lock().len, 0);
+        assert_eq!(kalloc.counts(), (NPOOL - 2 - NSTEAL, 2 + NSTEAL));
     }
 }
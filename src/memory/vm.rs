@@ -1,10 +1,10 @@
-use core::{panic, slice};
+use core::{cmp::min, panic, ptr, slice};
 
 use super::layout::{ETEXT, KERNBASE, PHYSTOP, UART, VIRTIO0};
-use crate::arch::{NPROC, PGSIZE};
+use crate::arch::{pg_round_down, pg_round_up, MAXVA, NPROC, PGSIZE};
 use crate::memory::kalloc::KALLOC;
 use crate::memory::layout::{kstack_start, PLIC, TRAMPOLINE, TRAPTEXT};
-use crate::process::master::INITCODE;
+use crate::process::master::{INITCODE, PMASTER};
 use riscv::asm::sfence_vma_all;
 use riscv::register::satp;
 use riscv::register::satp::Mode;
@@ -34,6 +34,19 @@ pub const PTE_R: u64 = 1 << 1; // readable
 pub const PTE_W: u64 = 1 << 2; // writable
 pub const PTE_X: u64 = 1 << 3; // executable
 pub const PTE_U: u64 = 1 << 4; // user can access
+pub const PTE_COW: u64 = 1 << 8; // RSW: copy-on-write page, writable once copied
+
+// Why an access to user memory failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum VmError {
+    BadAddress, // beyond the top of the user address space
+    NotMapped,  // no valid mapping for the page
+    NotUser,    // mapped, but not accessible from user mode
+    Permission, // mapped without the read or write permission needed
+    Remap,      // the page is already mapped
+    NoMemory,   // out of memory for a page-table page
+    TooLong,    // string did not fit in the destination
+}
 
 // The risc-v Sv39 scheme has three levels of page-table
 // pages. A page-table page contains 512 64-bit PTEs.
@@ -54,44 +67,44 @@ pub static mut KVM: Kvm = Kvm { root: 0 };
 impl Kvm {
     // create the kernel map
     pub fn init_kernel_page_table() {
-        let mut kvm = PageTable::create_table();
+        let mut kvm = PageTable::create_table()
+            .unwrap_or_else(|_| panic!("failed to create the kernel page table"));
+
         unsafe {
             // uart register
-            kvm.map(UART, UART, PGSIZE, PTE_R | PTE_W);
-            assert_eq!(kvm.translate(UART), UART);
+            Self::map(&mut kvm, UART, UART, PGSIZE, PTE_R | PTE_W);
 
             // PLIC
-            kvm.map(PLIC, PLIC, 0x400000, PTE_R | PTE_W);
-            assert_eq!(kvm.translate(PLIC), PLIC);
+            Self::map(&mut kvm, PLIC, PLIC, 0x400000, PTE_R | PTE_W);
 
             // virtio mmio disk interface
-            kvm.map(VIRTIO0, VIRTIO0, PGSIZE, PTE_R | PTE_W);
-            assert_eq!(kvm.translate(VIRTIO0), VIRTIO0);
+            Self::map(&mut kvm, VIRTIO0, VIRTIO0, PGSIZE, PTE_R | PTE_W);
 
             // map kernel text excutable and read-only
-            kvm.map(KERNBASE, KERNBASE, ETEXT - KERNBASE, PTE_R | PTE_X);
-
-            assert_eq!(kvm.translate(KERNBASE), KERNBASE);
+            Self::map(
+                &mut kvm,
+                KERNBASE,
+                KERNBASE,
+                ETEXT - KERNBASE,
+                PTE_R | PTE_X,
+            );
 
             // map kernel data and the physical RAM we'll make use of.
-            kvm.map(ETEXT, ETEXT, PHYSTOP - ETEXT, PTE_R | PTE_W);
-            assert_eq!(kvm.translate(ETEXT), ETEXT);
+            Self::map(&mut kvm, ETEXT, ETEXT, PHYSTOP - ETEXT, PTE_R | PTE_W);
 
             // map the trampoline for trap entry/exit to
             // the highest virtual address in the kernel.
-            kvm.map(TRAMPOLINE, TRAPTEXT, PGSIZE, PTE_R | PTE_X);
-            assert_eq!(kvm.translate(TRAMPOLINE), TRAPTEXT);
+            Self::map(&mut kvm, TRAMPOLINE, TRAPTEXT, PGSIZE, PTE_R | PTE_X);
 
             // allocate and map a kernel stack for each process.
             for p in 0..NPROC {
-                let phys_addr = if let Some(addr) = KALLOC.lock().alloc() {
+                let phys_addr = if let Some(addr) = KALLOC.alloc() {
                     addr
                 } else {
                     panic!("failed to create the kernel stack");
                 };
                 let virt_addr = kstack_start(p);
-                kvm.map(virt_addr, phys_addr, PGSIZE, PTE_R | PTE_W);
-                assert_eq!(kvm.translate(virt_addr), phys_addr);
+                Self::map(&mut kvm, virt_addr, phys_addr, PGSIZE, PTE_R | PTE_W);
             }
         }
 
@@ -100,6 +113,15 @@ impl Kvm {
         }
     }
 
+    // add a mapping to the kernel page table.
+    // only used when booting; a failure here is fatal.
+    fn map(kvm: &mut PageTable, virt_addr: u64, phys_addr: u64, range: u64, perm: u64) {
+        if let Err(err) = kvm.map(virt_addr, phys_addr, range, perm) {
+            panic!("kvmmap: {:?}", err);
+        }
+        assert_eq!(kvm.translate(virt_addr), Ok(phys_addr));
+    }
+
     // turn on the mmu hardware
     pub fn init_hart() {
         let ppn = unsafe { (KVM.root >> 12) as usize };
@@ -116,85 +138,41 @@ impl Kvm {
 impl PageTable {
     // map[virt_addr..virt_addr + range]
     // -> [phys_addr..phys_addr + range]
-    pub fn map(&mut self, virt_addr: u64, phys_addr: u64, range: u64, perm: u64) {
+    // On failure, the pages mapped so far are unmapped again.
+    pub fn map(
+        &mut self,
+        virt_addr: u64,
+        phys_addr: u64,
+        range: u64,
+        perm: u64,
+    ) -> Result<(), VmError> {
         assert_eq!(range & (4096 - 1), 0); // range must be 4096-aligned
-        let mut phys = phys_addr;
-        let mut virt = virt_addr;
-        let end = phys_addr + range;
-        while phys < end {
-            self.map_page(virt, phys, perm);
-            phys += PGSIZE;
-            virt += PGSIZE;
-        }
-    }
-
-    pub fn map_page(&mut self, virt_addr: u64, phys_addr: u64, perm: u64) {
-        // level 1
-        let lv1_ptes = &mut self.ptes;
-        let lv1_idx = Self::idx(virt_addr, PageTableLevel::Lv1);
-        let lv1_pte = lv1_ptes[lv1_idx];
-        let mut lv2_tbl = if Self::used(lv1_pte) {
-            PageTable::from_pte(lv1_pte)
-        } else {
-            // allocate a new page for table
-            PageTable::create_table()
-        };
-        lv1_ptes[lv1_idx] = lv2_tbl.to_pte() | PTE_V;
-
-        //-----------------------------------------------------
-        // level 2
-        let lv2_ptes = &mut lv2_tbl.ptes;
-        let lv2_idx = Self::idx(virt_addr, PageTableLevel::Lv2);
-        let lv2_pte = lv2_ptes[lv2_idx];
-        let mut lv3_tbl = if Self::used(lv2_pte) {
-            PageTable::from_pte(lv2_pte)
-        } else {
-            PageTable::create_table()
-        };
-        lv2_tbl.ptes[lv2_idx] = lv3_tbl.to_pte() | PTE_V;
+        let mut offset = 0;
+        while offset < range {
+            if let Err(err) = self.map_page(virt_addr + offset, phys_addr + offset, perm) {
+                self.unmap(virt_addr, offset / PGSIZE, false);
+                return Err(err);
+            }
+            offset += PGSIZE;
+        }
+        Ok(())
+    }
 
-        //-----------------------------------------------------
-        // level 3
-        let lv3_ptes = &mut lv3_tbl.ptes;
-        let lv3_idx = Self::idx(virt_addr, PageTableLevel::Lv3);
-        let mut lv3_pte = lv3_ptes[lv3_idx];
-        if Self::used(lv3_pte) {
-            panic!("Virtual memory: remap fault");
+    // map one page at virt_addr to phys_addr,
+    // allocating page-table pages as needed.
+    pub fn map_page(&mut self, virt_addr: u64, phys_addr: u64, perm: u64) -> Result<(), VmError> {
+        let pte = self.walk(virt_addr, true)?;
+        if Self::used(*pte) {
+            return Err(VmError::Remap);
         }
-        lv3_pte = Self::ppn(phys_addr) << 10 | perm | PTE_V;
-        lv3_tbl.ptes[lv3_idx] = lv3_pte;
+        *pte = Self::ppn(phys_addr) << 10 | perm | PTE_V;
+        Ok(())
     }
 
     // translate virtual address to physical address
-    pub fn translate(&self, virt_addr: u64) -> u64 {
-        // lv1
-        let lv1_ptes = &self.ptes;
-        let lv1_idx = Self::idx(virt_addr, PageTableLevel::Lv1);
-        let lv1_pte = lv1_ptes[lv1_idx];
-        let lv2_tbl = if Self::used(lv1_pte) {
-            PageTable::from_pte(lv1_pte)
-        } else {
-            panic!("Virtual memory: invalid virtual address");
-        };
-        //------------------------------------
-        // lv2
-        let lv2_ptes = &lv2_tbl.ptes;
-        let lv2_idx = Self::idx(virt_addr, PageTableLevel::Lv2);
-        let lv2_pte = lv2_ptes[lv2_idx];
-        let lv3_tbl = if Self::used(lv2_pte) {
-            PageTable::from_pte(lv2_pte)
-        } else {
-            panic!("Virtual memory: invalid virtual address");
-        };
-        //-----------------------------------------------------
-        // level 3
-        let lv3_ptes = &lv3_tbl.ptes;
-        let lv3_idx = Self::idx(virt_addr, PageTableLevel::Lv3);
-        let lv3_pte = lv3_ptes[lv3_idx];
-        if !Self::used(lv3_pte) {
-            panic!("Virtual memory: invalid virtual address");
-        }
-        ((lv3_pte >> 10) << 12) | Self::offset(virt_addr)
+    pub fn translate(&mut self, virt_addr: u64) -> Result<u64, VmError> {
+        let pte = *self.find_pte(virt_addr)?;
+        Ok(Self::pte_to_pa(pte) | Self::offset(virt_addr))
     }
 
     pub fn base_addr(&self) -> u64 {
@@ -216,17 +194,14 @@ impl PageTable {
         (addr >> 12) << 10
     }
 
-    pub fn create_table() -> Self {
-        let addr = if let Some(page) = KALLOC.lock().alloc() {
-            page
-        } else {
-            panic!("failed to create page table");
-        };
+    #[cfg_attr(feature = "kalloc-debug", track_caller)]
+    pub fn create_table() -> Result<Self, VmError> {
+        let addr = KALLOC.alloc().ok_or(VmError::NoMemory)?;
         let ptes = unsafe { slice::from_raw_parts_mut(addr as *mut u64, 512) };
         for pte in ptes.iter_mut() {
             *pte = 0;
         }
-        Self { ptes }
+        Ok(Self { ptes })
     }
 
     // pagetable utilities
@@ -251,14 +226,358 @@ impl PageTable {
         pte & PTE_V != 0
     }
 
+    fn pte_to_pa(pte: u64) -> u64 {
+        (pte >> 10) << 12
+    }
+
+    fn pte_flags(pte: u64) -> u64 {
+        pte & 0x3FF
+    }
+
+    // Return the address of the level 3 PTE for virt_addr.
+    // If alloc is true, create any required page-table pages;
+    // otherwise a missing page-table page is NotMapped.
+    // The returned PTE itself may still be invalid.
+    pub(crate) fn walk(&mut self, virt_addr: u64, alloc: bool) -> Result<&mut u64, VmError> {
+        if virt_addr >= MAXVA {
+            return Err(VmError::BadAddress);
+        }
+        let mut ptes: &mut [u64] = &mut *self.ptes;
+        for level in [PageTableLevel::Lv1, PageTableLevel::Lv2] {
+            let pte = &mut ptes[Self::idx(virt_addr, level)];
+            if !Self::used(*pte) {
+                if !alloc {
+                    return Err(VmError::NotMapped);
+                }
+                *pte = PageTable::create_table()?.to_pte() | PTE_V;
+            }
+            let next = *pte;
+            ptes = PageTable::from_pte(next).ptes;
+        }
+        Ok(&mut ptes[Self::idx(virt_addr, PageTableLevel::Lv3)])
+    }
+
+    // return the valid level 3 PTE that maps virt_addr.
+    fn find_pte(&mut self, virt_addr: u64) -> Result<&mut u64, VmError> {
+        let pte = self.walk(virt_addr, false)?;
+        if !Self::used(*pte) {
+            return Err(VmError::NotMapped);
+        }
+        Ok(pte)
+    }
+
+    // Remove npages of mappings starting from virt_addr. virt_addr must be
+    // page-aligned. Pages that were never touched (see uvmlazy) are skipped.
+    // Optionally free the physical memory.
+    pub(crate) fn unmap(&mut self, virt_addr: u64, npages: u64, free_phys: bool) {
+        if pg_round_down(virt_addr) != virt_addr {
+            panic!("unmap: not aligned");
+        }
+        let mut va = virt_addr;
+        while va < virt_addr + npages * PGSIZE {
+            let Ok(pte) = self.find_pte(va) else {
+                va += PGSIZE;
+                continue;
+            };
+            if Self::pte_flags(*pte) == PTE_V {
+                panic!("unmap: not a leaf");
+            }
+            if free_phys {
+                KALLOC.free(Self::pte_to_pa(*pte));
+            }
+            *pte = 0;
+            va += PGSIZE;
+        }
+    }
+
     pub(crate) fn uvmfirst(addr: u64) -> Option<()> {
         let mut pagetable = PageTable::from_addr(addr);
-        let first_page = KALLOC.lock().alloc()?;
-        pagetable.map(0, first_page, PGSIZE, PTE_W | PTE_R | PTE_X | PTE_U);
+        let first_page = KALLOC.alloc()?;
+        if pagetable
+            .map(0, first_page, PGSIZE, PTE_W | PTE_R | PTE_X | PTE_U)
+            .is_err()
+        {
+            KALLOC.free(first_page);
+            return None;
+        }
         let initcode = unsafe { slice::from_raw_parts_mut(first_page as *mut u8, INITCODE.len()) };
         for (i, e) in initcode.iter_mut().enumerate() {
             *e = INITCODE[i];
         }
         Some(())
     }
+
+    // Given a parent process's page table, share
+    // its memory with a child's page table.
+    // Writable pages become read-only copy-on-write pages
+    // in both, and are copied by uvmcow() on the first store.
+    // unmaps any shared pages on failure.
+    pub(crate) fn uvmcopy(old: u64, new: u64, sz: u64) -> Option<()> {
+        let mut old = PageTable::from_addr(old);
+        let mut new = PageTable::from_addr(new);
+        let mut virt_addr = 0;
+        while virt_addr < sz {
+            let Ok(pte) = old.find_pte(virt_addr) else {
+                // not touched yet; the child gets its own on demand.
+                virt_addr += PGSIZE;
+                continue;
+            };
+            if *pte & PTE_W != 0 {
+                *pte = (*pte & !PTE_W) | PTE_COW;
+            }
+            let phys_addr = Self::pte_to_pa(*pte);
+            let flags = Self::pte_flags(*pte);
+            if new.map_page(virt_addr, phys_addr, flags).is_err() {
+                new.unmap(0, virt_addr / PGSIZE, true);
+                return None;
+            }
+            KALLOC.incref(phys_addr);
+            virt_addr += PGSIZE;
+        }
+        Some(())
+    }
+
+    // Map a zeroed page at virt_addr, which lies below the process
+    // size sz but has not been touched since sbrk() reserved it.
+    // Used by usertrap() for load and store page faults.
+    #[cfg_attr(feature = "kalloc-debug", track_caller)]
+    pub(crate) fn uvmlazy(addr: u64, virt_addr: u64, sz: u64) -> Result<(), VmError> {
+        PageTable::from_addr(addr).lazy(pg_round_down(virt_addr), sz)
+    }
+
+    #[cfg_attr(feature = "kalloc-debug", track_caller)]
+    fn lazy(&mut self, va0: u64, sz: u64) -> Result<(), VmError> {
+        if va0 >= sz {
+            return Err(VmError::NotMapped);
+        }
+        // already mapped, e.g. a copy-on-write page:
+        // not ours to handle, so don't allocate anything.
+        if self.find_pte(va0).is_ok() {
+            return Err(VmError::Remap);
+        }
+        let page = KALLOC.alloc().ok_or(VmError::NoMemory)?;
+        unsafe {
+            ptr::write_bytes(page as *mut u8, 0, PGSIZE as usize);
+        }
+        if let Err(err) = self.map_page(va0, page, PTE_R | PTE_W | PTE_U) {
+            KALLOC.free(page);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // Resolve a store to the copy-on-write page holding virt_addr
+    // by giving this page table a private, writable copy.
+    // Used by usertrap() for store page faults.
+    #[cfg_attr(feature = "kalloc-debug", track_caller)]
+    pub(crate) fn uvmcow(addr: u64, virt_addr: u64) -> Result<(), VmError> {
+        PageTable::from_addr(addr).cow(pg_round_down(virt_addr))
+    }
+
+    #[cfg_attr(feature = "kalloc-debug", track_caller)]
+    fn cow(&mut self, va0: u64) -> Result<(), VmError> {
+        let pte = self.find_pte(va0)?;
+        if *pte & PTE_U == 0 {
+            return Err(VmError::NotUser);
+        }
+        if *pte & PTE_COW == 0 {
+            return Err(VmError::Permission);
+        }
+        let phys_addr = Self::pte_to_pa(*pte);
+        let flags = (Self::pte_flags(*pte) & !PTE_COW) | PTE_W;
+
+        if KALLOC.refcnt(phys_addr) == 1 {
+            // the last sharer keeps the page.
+            *pte = Self::ppn(phys_addr) << 10 | flags;
+            return Ok(());
+        }
+        let page = KALLOC.alloc().ok_or(VmError::NoMemory)?;
+        unsafe {
+            ptr::copy_nonoverlapping(phys_addr as *const u8, page as *mut u8, PGSIZE as usize);
+        }
+        *pte = Self::ppn(page) << 10 | flags;
+        KALLOC.decref(phys_addr);
+        Ok(())
+    }
+
+    // Free user memory pages,
+    // then free page-table pages.
+    pub(crate) fn uvmfree(addr: u64, sz: u64) {
+        let mut pagetable = PageTable::from_addr(addr);
+        if sz > 0 {
+            pagetable.unmap(0, pg_round_up(sz) / PGSIZE, true);
+        }
+        pagetable.freewalk();
+    }
+
+    // Allocate PTEs and physical memory to grow process from oldsz to
+    // newsz, which need not be page aligned.  Returns new size or None on error.
+    #[cfg_attr(feature = "kalloc-debug", track_caller)]
+    pub(crate) fn uvmalloc(addr: u64, oldsz: u64, newsz: u64, perm: u64) -> Option<u64> {
+        if newsz < oldsz {
+            return Some(oldsz);
+        }
+        let mut pagetable = PageTable::from_addr(addr);
+        let mut virt_addr = pg_round_up(oldsz);
+        while virt_addr < newsz {
+            let page = if let Some(page) = KALLOC.alloc() {
+                page
+            } else {
+                Self::uvmdealloc(addr, virt_addr, oldsz);
+                return None;
+            };
+            unsafe {
+                ptr::write_bytes(page as *mut u8, 0, PGSIZE as usize);
+            }
+            if pagetable.map_page(virt_addr, page, perm | PTE_U).is_err() {
+                KALLOC.free(page);
+                Self::uvmdealloc(addr, virt_addr, oldsz);
+                return None;
+            }
+            virt_addr += PGSIZE;
+        }
+        Some(newsz)
+    }
+
+    // Deallocate user pages to bring the process size from oldsz to
+    // newsz.  oldsz and newsz need not be page-aligned, nor does newsz
+    // need to be less than oldsz.  oldsz can be larger than the actual
+    // process size.  Returns the new process size.
+    pub(crate) fn uvmdealloc(addr: u64, oldsz: u64, newsz: u64) -> u64 {
+        if newsz >= oldsz {
+            return oldsz;
+        }
+        if pg_round_up(newsz) < pg_round_up(oldsz) {
+            let npages = (pg_round_up(oldsz) - pg_round_up(newsz)) / PGSIZE;
+            PageTable::from_addr(addr).unmap(pg_round_up(newsz), npages, true);
+        }
+        newsz
+    }
+
+    // mark a PTE invalid for user access.
+    // used by exec for the user stack guard page.
+    pub(crate) fn uvmclear(addr: u64, virt_addr: u64) {
+        let mut pagetable = PageTable::from_addr(addr);
+        let pte = pagetable
+            .find_pte(virt_addr)
+            .unwrap_or_else(|_| panic!("uvmclear"));
+        *pte &= !PTE_U;
+    }
+
+    // Recursively free page-table pages.
+    // All leaf mappings must already have been removed.
+    fn freewalk(&mut self) {
+        // there are 2^9 = 512 PTEs in a page table.
+        for pte in self.ptes.iter_mut() {
+            if Self::used(*pte) && *pte & (PTE_R | PTE_W | PTE_X) == 0 {
+                // this PTE points to a lower-level page table.
+                PageTable::from_pte(*pte).freewalk();
+                *pte = 0;
+            } else if Self::used(*pte) {
+                panic!("freewalk: leaf");
+            }
+        }
+        KALLOC.free(self.base_addr());
+    }
+
+    // Look up the physical address of the user page at va0,
+    // checking that user mode may access it with perm.
+    // Only used by the copy functions below, so that a bad pointer
+    // from user space becomes an error rather than a fault.
+    fn user_pa(&mut self, va0: u64, perm: u64) -> Result<u64, VmError> {
+        if va0 >= MAXVA {
+            return Err(VmError::BadAddress);
+        }
+        let pte = match self.find_pte(va0) {
+            Err(VmError::NotMapped) => {
+                self.fault_in(va0)?;
+                *self.find_pte(va0)?
+            }
+            pte => *pte?,
+        };
+        if pte & PTE_U == 0 {
+            return Err(VmError::NotUser);
+        }
+        if pte & perm != perm {
+            return Err(VmError::Permission);
+        }
+        Ok(Self::pte_to_pa(pte))
+    }
+
+    // The kernel touches a page the current process reserved
+    // but never used: allocate it, just as usertrap() would.
+    fn fault_in(&mut self, va0: u64) -> Result<(), VmError> {
+        let p = unsafe { &PMASTER.my_proc().context };
+        if p.pagetable != self.base_addr() {
+            return Err(VmError::NotMapped);
+        }
+        self.lazy(va0, p.sz)
+    }
+
+    // Copy from kernel to user.
+    // Copy src to virtual address dst_va in a given page table.
+    // Every destination page must be user-accessible and writable,
+    // possibly after copy-on-write.
+    pub(crate) fn copyout(addr: u64, mut dst_va: u64, mut src: &[u8]) -> Result<(), VmError> {
+        let mut pagetable = PageTable::from_addr(addr);
+        while !src.is_empty() {
+            let va0 = pg_round_down(dst_va);
+            let pa0 = match pagetable.user_pa(va0, PTE_W) {
+                Err(VmError::Permission) => {
+                    pagetable.cow(va0)?;
+                    pagetable.user_pa(va0, PTE_W)?
+                }
+                pa0 => pa0?,
+            };
+            let n = min(PGSIZE - (dst_va - va0), src.len() as u64) as usize;
+            unsafe {
+                ptr::copy_nonoverlapping(src.as_ptr(), (pa0 + (dst_va - va0)) as *mut u8, n);
+            }
+            src = &src[n..];
+            dst_va = va0 + PGSIZE;
+        }
+        Ok(())
+    }
+
+    // Copy from user to kernel.
+    // Copy dst.len() bytes to dst from virtual address src_va in a given page table.
+    // Every source page must be user-accessible and readable.
+    pub(crate) fn copyin(addr: u64, mut dst: &mut [u8], mut src_va: u64) -> Result<(), VmError> {
+        let mut pagetable = PageTable::from_addr(addr);
+        while !dst.is_empty() {
+            let va0 = pg_round_down(src_va);
+            let pa0 = pagetable.user_pa(va0, PTE_R)?;
+            let n = min(PGSIZE - (src_va - va0), dst.len() as u64) as usize;
+            unsafe {
+                ptr::copy_nonoverlapping((pa0 + (src_va - va0)) as *const u8, dst.as_mut_ptr(), n);
+            }
+            dst = &mut dst[n..];
+            src_va = va0 + PGSIZE;
+        }
+        Ok(())
+    }
+
+    // Copy a null-terminated string from user to kernel.
+    // Copy bytes to dst from virtual address src_va in a given page table,
+    // until a '\0', or dst is full.
+    // Return the length of the string, not counting the '\0'.
+    pub(crate) fn copyinstr(addr: u64, dst: &mut [u8], mut src_va: u64) -> Result<usize, VmError> {
+        let mut pagetable = PageTable::from_addr(addr);
+        let mut len = 0;
+        while len < dst.len() {
+            let va0 = pg_round_down(src_va);
+            let pa0 = pagetable.user_pa(va0, PTE_R)?;
+            let n = min(PGSIZE - (src_va - va0), (dst.len() - len) as u64) as usize;
+            let src = unsafe { slice::from_raw_parts((pa0 + (src_va - va0)) as *const u8, n) };
+            for &c in src {
+                dst[len] = c;
+                if c == 0 {
+                    return Ok(len);
+                }
+                len += 1;
+            }
+            src_va = va0 + PGSIZE;
+        }
+        Err(VmError::TooLong)
+    }
 }
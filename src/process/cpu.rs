@@ -85,8 +85,9 @@ pub(crate) struct TrapFrame {
 }
 
 impl TrapFrame {
+    #[cfg_attr(feature = "kalloc-debug", track_caller)]
     pub(crate) fn new() -> Option<*mut Self> {
-        let frame = KALLOC.lock().alloc()? as *mut TrapFrame;
+        let frame = KALLOC.alloc()? as *mut TrapFrame;
         unsafe {
             write_volatile(frame, TrapFrame::default());
         }
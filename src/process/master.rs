@@ -1,11 +1,14 @@
 use super::cpu::{Context, TrapFrame};
 use crate::arch::{intr_on, NPROC, PGSIZE};
+use crate::fs::log::{begin_op, end_op};
 use crate::layout::TRAPFRAME;
-use crate::lock::spinlock::SpinLock;
+use crate::lock::spinlock::{Guard, SpinLock};
+use crate::memory::kalloc::KALLOC;
 use crate::memory::layout::{kstack_end, kstack_start, TRAMPOLINE, TRAPTEXT};
 use crate::memory::vm::{PageTable, PTE_R, PTE_W, PTE_X};
 use crate::process::cpu::CMASTER;
 use crate::process::proc::{Proc, State};
+use crate::syscall::Errno;
 use crate::trap::forkret;
 use crate::{print, println};
 use core::cell::OnceCell;
@@ -20,11 +23,18 @@ extern "C" {
 
 pub(crate) static PID: SpinLock<usize> = SpinLock::new(0);
 
+// helps ensure that wakeups of wait()ing
+// parents are not lost. helps obey the
+// memory model when using p->parent.
+// must be acquired before any p->lock.
+pub(crate) static WAIT_LOCK: SpinLock<()> = SpinLock::new(());
+
 pub(crate) static mut PMASTER: PMaster = PMaster::new();
 
 // Per-CPU process scheduler.
 pub(crate) struct PMaster {
     procs: OnceCell<[Proc; NPROC]>,
+    initproc: Option<usize>, // index of the init process
 }
 
 unsafe impl Sync for PMaster {}
@@ -33,6 +43,7 @@ impl PMaster {
     pub(crate) const fn new() -> Self {
         Self {
             procs: OnceCell::new(),
+            initproc: None,
         }
     }
 
@@ -59,6 +70,16 @@ impl PMaster {
             .unwrap_or_else(|| panic!("not holding process"))
     }
 
+    // index of the current process in the process table.
+    fn my_pin(&self) -> usize {
+        unsafe {
+            CMASTER.push_off();
+            let pin = CMASTER.my_cpu().pin;
+            CMASTER.pop_off();
+            pin.unwrap_or_else(|| panic!("not holding process"))
+        }
+    }
+
     // Each CPU calls scheduler() after setting itself up.
     // Scheduler never returns.  It loops, doing:
     //  - choose a process to run.
@@ -72,20 +93,22 @@ impl PMaster {
             intr_on();
             for i in 0..NPROC {
                 let proc = &mut self[i];
-                my_cpu.proc = Some(proc as *mut Proc);
+                let proc_ptr = proc as *mut Proc;
                 let mut proc_info = proc.info.lock();
-                let proc_context = &mut proc.context;
                 if let State::Runnable = proc_info.state {
-                    println!("Switch to process index of {}", i);
                     // cpu should not master any process now.
                     assert!(my_cpu.pin.is_none());
                     proc_info.state = State::Running;
+                    my_cpu.proc = Some(proc_ptr);
+                    my_cpu.pin = Some(i);
+                    let proc_context = &mut proc.context;
                     let old = ptr::addr_of_mut!(my_cpu.context);
                     let new = ptr::addr_of_mut!(proc_context.context);
                     // Switch to chosen process.
                     unsafe {
                         swtch(old, new);
                     }
+                    my_cpu.proc = None;
                     my_cpu.pin = None; // cpu master no process now
                 }
             } // proc unlock
@@ -102,6 +125,8 @@ impl PMaster {
     pub(crate) fn sched(&self) {
         assert!(!sstatus::read().sie());
         let cpu = unsafe { CMASTER.my_cpu_mut() };
+        assert_eq!(cpu.nlock, 1, "sched locks");
+        let intena = cpu.intr;
         let proc_context = unsafe { &mut self.my_proc().context };
         let old = ptr::addr_of_mut!(proc_context.context);
         let new = ptr::addr_of_mut!(cpu.context);
@@ -109,6 +134,10 @@ impl PMaster {
         unsafe {
             swtch(old, new);
         }
+        // we may be running on another cpu now.
+        unsafe {
+            CMASTER.my_cpu_mut().intr = intena;
+        }
     }
 
     // Give up the CPU for one scheduling round.
@@ -119,6 +148,80 @@ impl PMaster {
         self.sched();
     }
 
+    // Atomically release lock and sleep on chan.
+    // Reacquires lock when awakened.
+    pub(crate) fn sleep<'a, T>(&self, chan: usize, guard: Guard<'a, T>) -> Guard<'a, T> {
+        let p = unsafe { self.my_proc() };
+
+        // Must acquire p->lock in order to
+        // change p->state and then call sched.
+        // Once we hold p->lock, we can be
+        // guaranteed that we won't miss any wakeup
+        // (wakeup locks p->lock),
+        // so it's okay to release lk.
+        let mut proc_info = p.info.lock();
+        let lock = guard.release();
+
+        // Go to sleep.
+        proc_info.chan = Some(chan);
+        proc_info.state = State::Sleeping;
+
+        self.sched();
+
+        // Tidy up.
+        proc_info.chan = None;
+
+        // Reacquire original lock.
+        drop(proc_info);
+        lock.lock()
+    }
+
+    // Wake up all processes sleeping on chan.
+    // Must be called without any p->lock.
+    pub(crate) fn wakeup(&self, chan: usize) {
+        let me = unsafe {
+            CMASTER.push_off();
+            let pin = CMASTER.my_cpu().pin;
+            CMASTER.pop_off();
+            pin
+        };
+        for i in 0..NPROC {
+            if me == Some(i) {
+                continue;
+            }
+            let mut proc_info = self[i].info.lock();
+            if let State::Sleeping = proc_info.state {
+                if proc_info.chan == Some(chan) {
+                    proc_info.state = State::Runnable;
+                }
+            }
+        }
+    }
+
+    // Print a process listing to console.  For debugging.
+    // Runs when user types ^P on console.
+    pub(crate) fn procdump(&self) {
+        println!();
+        for i in 0..NPROC {
+            let proc = &self[i];
+            let proc_info = proc.info.lock();
+            if let State::Unused = proc_info.state {
+                continue;
+            }
+            println!(
+                "{} {:?} {}",
+                proc.context.pid,
+                proc_info.state,
+                proc.context.name()
+            );
+        }
+    }
+
+    // the channel a process sleeps on while it waits for its children.
+    fn wait_chan(&self, pin: usize) -> usize {
+        &self[pin] as *const Proc as usize
+    }
+
     // Look in the process table for an UNUSED proc.
     // If found, initialize state required to run in the kernel,
     // and return with p->lock held.
@@ -131,23 +234,19 @@ impl PMaster {
             if let State::Unused = proc_info.state {
                 let new_pid = Self::alloc_pid();
                 proc_context.pid = new_pid;
-                proc_info.state = State::Used;
                 // Allocate a trapframe page.
                 proc_context.trapframe = TrapFrame::new()?;
 
-                // An empty user page tab
-                let mut pagetable = PageTable::create_table();
-                unsafe {
-                    pagetable.map(TRAMPOLINE, TRAPTEXT, PGSIZE, PTE_R | PTE_X);
-                    pagetable.map(
-                        TRAPFRAME,
-                        proc_context.trapframe as u64,
-                        PGSIZE,
-                        PTE_R | PTE_W,
-                    );
-                }
-
-                proc_context.pagetable = pagetable.base_addr();
+                // An empty user page table.
+                proc_context.pagetable = match Self::proc_pagetable(proc_context.trapframe) {
+                    Some(pagetable) => pagetable,
+                    None => {
+                        KALLOC.free(proc_context.trapframe as u64);
+                        proc_context.trapframe = ptr::null_mut();
+                        return None;
+                    }
+                };
+                proc_info.state = State::Used;
                 // Set up new context to start executing at forkret,
                 // which returns to user space.
                 let mut context = Context::default();
@@ -162,6 +261,261 @@ impl PMaster {
         None
     }
 
+    // free a proc structure and the data hanging from it,
+    // including user pages.
+    pub(crate) fn free_proc(&mut self, pin: usize) {
+        let proc = &mut self[pin];
+        let mut proc_info = proc.info.lock();
+        let proc_context = &mut proc.context;
+        if !proc_context.trapframe.is_null() {
+            KALLOC.free(proc_context.trapframe as u64);
+        }
+        proc_context.trapframe = ptr::null_mut();
+        if proc_context.pagetable != 0 {
+            Self::proc_freepagetable(proc_context.pagetable, proc_context.sz);
+        }
+        proc_context.pagetable = 0;
+        proc_context.sz = 0;
+        proc_context.pid = 0;
+        proc_context.parent = None;
+        proc_context.name = [0; 16];
+        proc_info.chan = None;
+        proc_info.killed = false;
+        proc_info.xstate = 0;
+        proc_info.state = State::Unused;
+    }
+
+    // Grow or shrink user memory by n bytes.
+    // Growing only reserves the range; pages are allocated
+    // when first touched (see PageTable::uvmlazy).
+    // Fails with ENOMEM, leaving the size unchanged, if the new break
+    // would fall outside user memory.
+    pub(crate) fn growproc(&mut self, n: i64) -> Result<(), Errno> {
+        let p = unsafe { &mut self.my_proc().context };
+        let sz = p.sz;
+        let newsz = sz.checked_add_signed(n).ok_or(Errno::ENOMEM)?;
+        if n > 0 {
+            if newsz > TRAPFRAME {
+                return Err(Errno::ENOMEM);
+            }
+            p.sz = newsz;
+        } else if n < 0 {
+            p.sz = PageTable::uvmdealloc(p.pagetable, sz, newsz);
+        }
+        Ok(())
+    }
+
+    // Create a new process, copying the parent.
+    // Sets up child kernel stack to return as if from fork() system call.
+    pub(crate) fn fork(&mut self) -> Option<usize> {
+        let p = unsafe { &self.my_proc().context };
+        let (pagetable, sz, trapframe, name) = (p.pagetable, p.sz, p.trapframe, p.name);
+        let ofile = p.ofile.clone();
+        let cwd = p.cwd.clone();
+        let parent = self.my_pin();
+        let pin = self.alloc()?;
+        let np = &mut self[pin];
+
+        // Copy user memory from parent to child.
+        if PageTable::uvmcopy(pagetable, np.context.pagetable, sz).is_none() {
+            self.free_proc(pin);
+            return None;
+        }
+        np.context.sz = sz;
+        np.context.name = name;
+
+        unsafe {
+            // copy saved user registers.
+            ptr::copy_nonoverlapping(trapframe, np.context.trapframe, 1);
+            // Cause fork to return 0 in the child.
+            (*np.context.trapframe).a0 = 0;
+        }
+
+        // increment reference counts on open file descriptors.
+        np.context.ofile = ofile;
+        np.context.cwd = cwd;
+
+        let pid = np.context.pid;
+
+        let wait_guard = WAIT_LOCK.lock();
+        np.context.parent = Some(parent);
+        drop(wait_guard);
+
+        np.info.lock().state = State::Runnable;
+        Some(pid)
+    }
+
+    // Pass p's abandoned children to init.
+    // Caller must hold WAIT_LOCK.
+    fn reparent(&mut self, pin: usize) {
+        let initproc = self.initproc;
+        for i in 0..NPROC {
+            let child = &mut self[i];
+            if child.context.parent == Some(pin) {
+                child.context.parent = initproc;
+                if let Some(init) = initproc {
+                    self.wakeup(self.wait_chan(init));
+                }
+            }
+        }
+    }
+
+    // Exit the current process.  Does not return.
+    // An exited process remains in the zombie state
+    // until its parent calls wait().
+    pub(crate) fn exit(&mut self, status: i32) -> ! {
+        let pin = self.my_pin();
+        if self.initproc == Some(pin) {
+            panic!("init exiting");
+        }
+
+        // Close all open files.
+        for f in self[pin].context.ofile.iter_mut() {
+            *f = None;
+        }
+
+        // Release the current directory.
+        begin_op();
+        self[pin].context.cwd = None;
+        end_op();
+
+        let wait_guard = WAIT_LOCK.lock();
+
+        // Give any children to init.
+        self.reparent(pin);
+
+        // Parent might be sleeping in wait().
+        if let Some(parent) = self[pin].context.parent {
+            self.wakeup(self.wait_chan(parent));
+        }
+
+        let mut proc_info = self[pin].info.lock();
+        proc_info.xstate = status;
+        proc_info.state = State::Zombie;
+
+        drop(wait_guard);
+
+        // Jump into the scheduler, never to return.
+        self.sched();
+        panic!("zombie exit");
+    }
+
+    // Kill the process with the given pid.
+    // The victim won't exit until it tries to return
+    // to user space (see usertrap() in trap/mod.rs).
+    pub(crate) fn kill(&self, pid: usize) -> Result<(), Errno> {
+        for i in 0..NPROC {
+            let proc = &self[i];
+            let mut proc_info = proc.info.lock();
+            if let State::Unused = proc_info.state {
+                continue;
+            }
+            if proc.context.pid == pid {
+                proc_info.killed = true;
+                if let State::Sleeping = proc_info.state {
+                    // Wake process from sleep().
+                    proc_info.state = State::Runnable;
+                }
+                return Ok(());
+            }
+        }
+        Err(Errno::ESRCH)
+    }
+
+    // mark the current process as killed.
+    pub(crate) fn set_killed(&self) {
+        let p = unsafe { self.my_proc() };
+        p.info.lock().killed = true;
+    }
+
+    // has the current process been killed?
+    pub(crate) fn killed(&self) -> bool {
+        let p = unsafe { self.my_proc() };
+        p.info.lock().killed
+    }
+
+    // Wait for a child process to exit and return its pid.
+    // Copy the child's exit status to addr, unless addr is 0.
+    // Fails with ECHILD if this process has no children.
+    pub(crate) fn wait(&mut self, addr: u64) -> Result<usize, Errno> {
+        let pin = self.my_pin();
+        let pagetable = self[pin].context.pagetable;
+        let mut wait_guard = WAIT_LOCK.lock();
+        loop {
+            // Scan through table looking for exited children.
+            let mut havekids = false;
+            for i in 0..NPROC {
+                if self[i].context.parent != Some(pin) {
+                    continue;
+                }
+                havekids = true;
+                // make sure the child isn't still in exit() or swtch().
+                let child = &self[i];
+                let child_info = child.info.lock();
+                if let State::Zombie = child_info.state {
+                    // Found one.
+                    let pid = child.context.pid;
+                    let xstate = child_info.xstate.to_ne_bytes();
+                    if addr != 0 {
+                        PageTable::copyout(pagetable, addr, &xstate)?;
+                    }
+                    drop(child_info);
+                    self.free_proc(i);
+                    drop(wait_guard);
+                    return Ok(pid);
+                }
+            }
+
+            // No point waiting if we don't have any children.
+            if !havekids {
+                return Err(Errno::ECHILD);
+            }
+            if self.killed() {
+                return Err(Errno::EINTR);
+            }
+
+            // Wait for a child to exit.
+            wait_guard = self.sleep(self.wait_chan(pin), wait_guard);
+        }
+    }
+
+    // Create a user page table for a given process, with no user memory,
+    // but with trampoline and trapframe pages.
+    pub(crate) fn proc_pagetable(trapframe: *mut TrapFrame) -> Option<u64> {
+        let mut pagetable = PageTable::create_table().ok()?;
+        let base = pagetable.base_addr();
+
+        // map the trampoline code (for system call return)
+        // at the highest user virtual address.
+        // only the supervisor uses it, on the way
+        // to/from user space, so not PTE_U.
+        if unsafe { pagetable.map(TRAMPOLINE, TRAPTEXT, PGSIZE, PTE_R | PTE_X) }.is_err() {
+            PageTable::uvmfree(base, 0);
+            return None;
+        }
+
+        // map the trapframe page just below the trampoline page, for
+        // trampoline.S.
+        if pagetable
+            .map(TRAPFRAME, trapframe as u64, PGSIZE, PTE_R | PTE_W)
+            .is_err()
+        {
+            pagetable.unmap(TRAMPOLINE, 1, false);
+            PageTable::uvmfree(base, 0);
+            return None;
+        }
+        Some(base)
+    }
+
+    // Free a process's page table, and free the
+    // physical memory it refers to.
+    pub(crate) fn proc_freepagetable(pagetable: u64, sz: u64) {
+        let mut table = PageTable::from_addr(pagetable);
+        table.unmap(TRAMPOLINE, 1, false);
+        table.unmap(TRAPFRAME, 1, false);
+        PageTable::uvmfree(pagetable, sz);
+    }
+
     fn alloc_pid() -> usize {
         let mut curr = PID.lock();
         *curr = curr.add(1);
@@ -170,15 +524,20 @@ impl PMaster {
 
     // Set up first user process.
     pub(crate) fn user_init(&mut self) {
-        let proc = if let Some(pin) = self.alloc() {
-            &mut self[pin]
+        let pin = if let Some(pin) = self.alloc() {
+            pin
         } else {
             panic!("failed to allocate the first process");
         };
+        self.initproc = Some(pin);
+        let proc = &mut self[pin];
         if PageTable::uvmfirst(proc.context.pagetable).is_none() {
             panic!("failed to create page table for the first procress");
         }
 
+        proc.context.sz = PGSIZE;
+        proc.context.set_name(b"initcode");
+
         let trapframe = proc.context.trapframe;
         unsafe {
             (*trapframe).epc = 0; // user program counter
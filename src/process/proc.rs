@@ -1,4 +1,6 @@
 use super::cpu::{Context, TrapFrame};
+use crate::arch::NOFILE;
+use crate::fs::{file::File, inode::Inode};
 use crate::lock::spinlock::SpinLock;
 use core::{cell::OnceCell, ptr};
 
@@ -22,6 +24,9 @@ pub(crate) struct Proc {
 
 pub(crate) struct ProcInfo {
     pub(crate) state: State,
+    pub(crate) chan: Option<usize>, // If Some, sleeping on chan
+    pub(crate) killed: bool,        // If true, have been killed
+    pub(crate) xstate: i32,         // Exit status to be returned to parent's wait
     pub(crate) _hart_id: usize,
 }
 
@@ -31,12 +36,20 @@ pub(crate) struct ProcContext {
     pub(crate) pid: usize,
     pub(crate) trapframe: *mut TrapFrame,
     pub(crate) pagetable: u64,
+    pub(crate) sz: u64,                       // Size of process memory (bytes)
+    pub(crate) parent: Option<usize>,         // Parent process, guarded by WAIT_LOCK
+    pub(crate) ofile: [Option<File>; NOFILE], // Open files
+    pub(crate) cwd: Option<Inode>,            // Current directory
+    pub(crate) name: [u8; 16],                // Process name (debugging)
 }
 
 impl ProcInfo {
     pub(crate) fn new() -> Self {
         Self {
             state: State::Unused,
+            chan: None,
+            killed: false,
+            xstate: 0,
             _hart_id: 42,
         }
     }
@@ -49,9 +62,31 @@ impl ProcContext {
             context: Context::default(),
             pid: 0,
             pagetable: 0,
+            sz: 0,
+            parent: None,
+            ofile: [const { None }; NOFILE],
+            cwd: None,
+            name: [0; 16],
             trapframe: ptr::null_mut(),
         }
     }
+
+    // remember name, truncated and nul-padded, for debugging.
+    pub(crate) fn set_name(&mut self, name: &[u8]) {
+        let len = name.len().min(self.name.len() - 1);
+        self.name = [0; 16];
+        self.name[..len].copy_from_slice(&name[..len]);
+    }
+
+    // the name as text, for diagnostics.
+    pub(crate) fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("???")
+    }
 }
 
 impl Proc {
@@ -0,0 +1,52 @@
+use crate::memory::vm::VmError;
+
+// Error numbers returned to user space.
+// A failed system call returns -errno in a0; the numbers
+// follow Linux so user programs can share the usual tables.
+#[allow(dead_code, clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Errno {
+    EPERM = 1,         // Operation not permitted
+    ENOENT = 2,        // No such file or directory
+    ESRCH = 3,         // No such process
+    EINTR = 4,         // Interrupted system call
+    EIO = 5,           // I/O error
+    ENXIO = 6,         // No such device or address
+    E2BIG = 7,         // Argument list too long
+    ENOEXEC = 8,       // Exec format error
+    EBADF = 9,         // Bad file number
+    ECHILD = 10,       // No child processes
+    EAGAIN = 11,       // Try again
+    ENOMEM = 12,       // Out of memory
+    EFAULT = 14,       // Bad address
+    EEXIST = 17,       // File exists
+    EXDEV = 18,        // Cross-device link
+    ENOTDIR = 20,      // Not a directory
+    EISDIR = 21,       // Is a directory
+    EINVAL = 22,       // Invalid argument
+    ENFILE = 23,       // File table overflow
+    EMFILE = 24,       // Too many open files
+    ENOSPC = 28,       // No space left on device
+    EPIPE = 32,        // Broken pipe
+    ENAMETOOLONG = 36, // File name too long
+    ENOSYS = 38,       // Invalid system call number
+    ENOTEMPTY = 39,    // Directory not empty
+}
+
+impl Errno {
+    // the value placed in a0 for this error.
+    pub(crate) fn as_ret(self) -> u64 {
+        -(self as i64) as u64
+    }
+}
+
+// a bad user pointer.
+impl From<VmError> for Errno {
+    fn from(err: VmError) -> Self {
+        match err {
+            VmError::TooLong => Errno::ENAMETOOLONG,
+            VmError::NoMemory => Errno::ENOMEM,
+            _ => Errno::EFAULT,
+        }
+    }
+}
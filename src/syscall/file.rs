@@ -0,0 +1,321 @@
+use core::mem::size_of;
+
+use super::{Errno, SysCall, SysResult};
+use crate::arch::{MAXPATH, NDEV};
+use crate::fs::dir::{namei, nameiparent};
+use crate::fs::file::{pipealloc, File, FileType};
+use crate::fs::inode::{ialloc, Inode};
+use crate::fs::layout::{as_bytes, Dirent, T_DEVICE, T_DIR, T_FILE};
+use crate::fs::log::{begin_op, end_op};
+use crate::memory::vm::PageTable;
+use crate::process::{cpu::TrapFrame, master::PMASTER};
+
+// open() modes
+const O_RDONLY: u64 = 0x000;
+const O_WRONLY: u64 = 0x001;
+const O_RDWR: u64 = 0x002;
+const O_CREATE: u64 = 0x200;
+const O_TRUNC: u64 = 0x400;
+
+// Fetch the nth word-sized system call argument as a file descriptor
+// and return both the descriptor and the corresponding open file.
+// The file is a new reference, so it stays open for the rest of
+// the system call even if the descriptor is closed meanwhile.
+fn argfd(trapframe: *mut TrapFrame, n: u8) -> Result<(usize, File), Errno> {
+    let fd = SysCall::nth_arg(trapframe, n)? as usize;
+    let p = unsafe { &PMASTER.my_proc().context };
+    match p.ofile.get(fd) {
+        Some(Some(f)) => Ok((fd, f.clone())),
+        _ => Err(Errno::EBADF),
+    }
+}
+
+// Allocate a file descriptor for the given file.
+// Takes over file reference from caller on success.
+fn fdalloc(f: File) -> Result<usize, Errno> {
+    let p = unsafe { &mut PMASTER.my_proc().context };
+    let fd = p
+        .ofile
+        .iter()
+        .position(Option::is_none)
+        .ok_or(Errno::EMFILE)?;
+    p.ofile[fd] = Some(f);
+    Ok(fd)
+}
+
+pub(super) fn sys_dup(trapframe: *mut TrapFrame) -> SysResult {
+    let (_, f) = argfd(trapframe, 0)?;
+    let fd = fdalloc(f)?;
+    Ok(fd as u64)
+}
+
+pub(super) fn sys_read(trapframe: *mut TrapFrame) -> SysResult {
+    let (_, f) = argfd(trapframe, 0)?;
+    let addr = SysCall::nth_arg(trapframe, 1)?;
+    let n = SysCall::nth_arg(trapframe, 2)? as usize;
+    let n = f.read(addr, n)?;
+    Ok(n as u64)
+}
+
+pub(super) fn sys_write(trapframe: *mut TrapFrame) -> SysResult {
+    let (_, f) = argfd(trapframe, 0)?;
+    let addr = SysCall::nth_arg(trapframe, 1)?;
+    let n = SysCall::nth_arg(trapframe, 2)? as usize;
+    let n = f.write(addr, n)?;
+    Ok(n as u64)
+}
+
+pub(super) fn sys_close(trapframe: *mut TrapFrame) -> SysResult {
+    let (fd, _) = argfd(trapframe, 0)?;
+    let p = unsafe { &mut PMASTER.my_proc().context };
+    p.ofile[fd] = None;
+    Ok(0)
+}
+
+pub(super) fn sys_fstat(trapframe: *mut TrapFrame) -> SysResult {
+    let (_, f) = argfd(trapframe, 0)?;
+    let addr = SysCall::nth_arg(trapframe, 1)?; // user pointer to struct stat
+    f.stat(addr)?;
+    Ok(0)
+}
+
+pub(super) fn sys_pipe(trapframe: *mut TrapFrame) -> SysResult {
+    let fdarray = SysCall::nth_arg(trapframe, 0)?; // user pointer to array of two integers
+    let (rf, wf) = pipealloc().ok_or(Errno::ENFILE)?;
+    let fd0 = fdalloc(rf)?;
+    let p = unsafe { &mut PMASTER.my_proc().context };
+    let fd1 = match fdalloc(wf) {
+        Ok(fd1) => fd1,
+        Err(err) => {
+            p.ofile[fd0] = None;
+            return Err(err);
+        }
+    };
+
+    let mut fds = [0; 2 * size_of::<i32>()];
+    fds[..4].copy_from_slice(&(fd0 as i32).to_ne_bytes());
+    fds[4..].copy_from_slice(&(fd1 as i32).to_ne_bytes());
+    if let Err(err) = PageTable::copyout(p.pagetable, fdarray, &fds) {
+        p.ofile[fd0] = None;
+        p.ofile[fd1] = None;
+        return Err(err.into());
+    }
+    Ok(0)
+}
+
+pub(super) fn sys_open(trapframe: *mut TrapFrame) -> SysResult {
+    let mut path = [0; MAXPATH];
+    let path = SysCall::arg_str(trapframe, 0, &mut path)?;
+    let omode = SysCall::nth_arg(trapframe, 1)?;
+    begin_op();
+    let ret = open(path, omode);
+    end_op();
+    Ok(ret? as u64)
+}
+
+fn open(path: &[u8], omode: u64) -> Result<usize, Errno> {
+    let ip = if omode & O_CREATE != 0 {
+        create(path, T_FILE, 0, 0)?
+    } else {
+        let ip = namei(path).ok_or(Errno::ENOENT)?;
+        if ip.lock().typ == T_DIR && omode != O_RDONLY {
+            return Err(Errno::EISDIR);
+        }
+        ip
+    };
+
+    let guard = ip.lock();
+    let (typ, major) = (guard.typ, guard.major);
+    drop(guard);
+    if typ == T_DEVICE && !(0..NDEV as i16).contains(&major) {
+        return Err(Errno::ENXIO);
+    }
+
+    let ftype = if typ == T_DEVICE {
+        FileType::Device(ip.clone(), major)
+    } else {
+        FileType::Inode(ip.clone())
+    };
+    let readable = omode & O_WRONLY == 0;
+    let writable = omode & (O_WRONLY | O_RDWR) != 0;
+    let f = File::alloc(ftype, readable, writable).ok_or(Errno::ENFILE)?;
+    let fd = fdalloc(f)?;
+
+    if omode & O_TRUNC != 0 && typ == T_FILE {
+        ip.lock().trunc();
+    }
+    Ok(fd)
+}
+
+pub(super) fn sys_chdir(trapframe: *mut TrapFrame) -> SysResult {
+    let mut path = [0; MAXPATH];
+    let path = SysCall::arg_str(trapframe, 0, &mut path)?;
+    begin_op();
+    let ret = chdir(path);
+    end_op();
+    ret.map(|_| 0)
+}
+
+fn chdir(path: &[u8]) -> Result<(), Errno> {
+    let ip = namei(path).ok_or(Errno::ENOENT)?;
+    if ip.lock().typ != T_DIR {
+        return Err(Errno::ENOTDIR);
+    }
+    let p = unsafe { &mut PMASTER.my_proc().context };
+    p.cwd = Some(ip);
+    Ok(())
+}
+
+pub(super) fn sys_mkdir(trapframe: *mut TrapFrame) -> SysResult {
+    let mut path = [0; MAXPATH];
+    let path = SysCall::arg_str(trapframe, 0, &mut path)?;
+    begin_op();
+    let ret = create(path, T_DIR, 0, 0).map(drop);
+    end_op();
+    ret.map(|_| 0)
+}
+
+pub(super) fn sys_mknod(trapframe: *mut TrapFrame) -> SysResult {
+    let mut path = [0; MAXPATH];
+    let path = SysCall::arg_str(trapframe, 0, &mut path)?;
+    let major = SysCall::nth_arg(trapframe, 1)? as i16;
+    let minor = SysCall::nth_arg(trapframe, 2)? as i16;
+    begin_op();
+    let ret = create(path, T_DEVICE, major, minor).map(drop);
+    end_op();
+    ret.map(|_| 0)
+}
+
+pub(super) fn sys_link(trapframe: *mut TrapFrame) -> SysResult {
+    let mut old = [0; MAXPATH];
+    let mut new = [0; MAXPATH];
+    let old = SysCall::arg_str(trapframe, 0, &mut old)?;
+    let new = SysCall::arg_str(trapframe, 1, &mut new)?;
+    begin_op();
+    let ret = link(old, new);
+    end_op();
+    ret.map(|_| 0)
+}
+
+// Create the path new as a link to the same inode as old.
+fn link(old: &[u8], new: &[u8]) -> Result<(), Errno> {
+    let ip = namei(old).ok_or(Errno::ENOENT)?;
+    let mut guard = ip.lock();
+    if guard.typ == T_DIR {
+        return Err(Errno::EPERM);
+    }
+    guard.nlink += 1;
+    guard.update();
+    let (dev, inum) = (guard.dev, guard.inum);
+    drop(guard);
+
+    let linked = (|| {
+        let (dp, name) = nameiparent(new).ok_or(Errno::ENOENT)?;
+        let mut dp = dp.lock();
+        if dp.dev != dev {
+            return Err(Errno::EXDEV);
+        }
+        if dp.dirlookup(name).is_some() {
+            return Err(Errno::EEXIST);
+        }
+        dp.dirlink(name, inum).ok_or(Errno::ENOSPC)
+    })();
+    if linked.is_err() {
+        let mut guard = ip.lock();
+        guard.nlink -= 1;
+        guard.update();
+    }
+    linked
+}
+
+pub(super) fn sys_unlink(trapframe: *mut TrapFrame) -> SysResult {
+    let mut path = [0; MAXPATH];
+    let path = SysCall::arg_str(trapframe, 0, &mut path)?;
+    begin_op();
+    let ret = unlink(path);
+    end_op();
+    ret.map(|_| 0)
+}
+
+fn unlink(path: &[u8]) -> Result<(), Errno> {
+    let (dp, name) = nameiparent(path).ok_or(Errno::ENOENT)?;
+    let mut dp = dp.lock();
+
+    // Cannot unlink "." or "..".
+    if name == b"." || name == b".." {
+        return Err(Errno::EINVAL);
+    }
+
+    let (ip, off) = dp.dirlookup(name).ok_or(Errno::ENOENT)?;
+    let mut ip = ip.lock();
+
+    if ip.nlink < 1 {
+        panic!("unlink: nlink < 1");
+    }
+    if ip.typ == T_DIR && !ip.is_dir_empty() {
+        return Err(Errno::ENOTEMPTY);
+    }
+
+    let de = Dirent::default();
+    if dp.write(as_bytes(&de), off) != Some(size_of::<Dirent>()) {
+        panic!("unlink: writei");
+    }
+    if ip.typ == T_DIR {
+        dp.nlink -= 1;
+        dp.update();
+    }
+    drop(dp);
+
+    ip.nlink -= 1;
+    ip.update();
+    Ok(())
+}
+
+// Create a new inode of type typ at path, linked into its parent.
+// Opening an existing file for T_FILE returns that file instead.
+fn create(path: &[u8], typ: i16, major: i16, minor: i16) -> Result<Inode, Errno> {
+    let (dp, name) = nameiparent(path).ok_or(Errno::ENOENT)?;
+    let mut dp = dp.lock();
+
+    if let Some((ip, _)) = dp.dirlookup(name) {
+        drop(dp);
+        let existing = ip.lock().typ;
+        if typ == T_FILE && (existing == T_FILE || existing == T_DEVICE) {
+            return Ok(ip);
+        }
+        return Err(Errno::EEXIST);
+    }
+
+    let ip = ialloc(dp.dev, typ).ok_or(Errno::ENOSPC)?;
+    let mut guard = ip.lock();
+    guard.major = major;
+    guard.minor = minor;
+    guard.nlink = 1;
+    guard.update();
+
+    let inum = guard.inum;
+    let linked = (|| {
+        if typ == T_DIR {
+            // Create . and .. entries.
+            // No nlink += 1 for ".": avoid cyclic ref count.
+            guard.dirlink(b".", inum)?;
+            guard.dirlink(b"..", dp.inum)?;
+        }
+        dp.dirlink(name, inum)
+    })();
+    if linked.is_none() {
+        // something went wrong. de-allocate ip.
+        guard.nlink = 0;
+        guard.update();
+        return Err(Errno::ENOSPC);
+    }
+
+    if typ == T_DIR {
+        // now that success is guaranteed:
+        dp.nlink += 1; // for ".."
+        dp.update();
+    }
+
+    drop(guard);
+    Ok(ip)
+}
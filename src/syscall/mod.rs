@@ -1,6 +1,13 @@
+use crate::memory::vm::PageTable;
+use crate::process::master::PMASTER;
 use crate::{print, println, process::cpu::TrapFrame};
 
-#[allow(dead_code)]
+pub(crate) use self::errno::Errno;
+
+mod errno;
+mod file;
+mod proc;
+
 pub(crate) enum SysCall {
     Fork,
     Exit,
@@ -26,6 +33,10 @@ pub(crate) enum SysCall {
     Log, // log for test
 }
 
+// the result of a system call handler:
+// a value for a0, or an error returned to user space as -errno.
+pub(crate) type SysResult = Result<u64, Errno>;
+
 pub(crate) fn handle(trapframe: *mut TrapFrame) {
     unsafe {
         // sepc points to the ecall instruction,
@@ -33,31 +44,78 @@ pub(crate) fn handle(trapframe: *mut TrapFrame) {
         (*trapframe).epc += 4;
     }
 
-    let syscall = SysCall::from_trapframe(trapframe);
-
-    match syscall {
-        SysCall::Log => {
-            test_log(trapframe);
+    let ret = match SysCall::from_trapframe(trapframe) {
+        Some(syscall) => syscall.dispatch(trapframe),
+        None => {
+            let p = unsafe { &PMASTER.my_proc().context };
+            println!("{} {}: unknown sys call {}", p.pid, p.name(), unsafe {
+                (*trapframe).a7
+            });
+            Err(Errno::ENOSYS)
         }
-        _ => unimplemented!("unimplemented syscall"),
+    };
+
+    unsafe {
+        (*trapframe).a0 = match ret {
+            Ok(val) => val,
+            Err(errno) => errno.as_ret(),
+        };
     }
 }
 
 impl SysCall {
-    unsafe fn nth_arg(trapframe: *mut TrapFrame, n: u8) -> u64 {
+    fn dispatch(self, trapframe: *mut TrapFrame) -> SysResult {
+        match self {
+            SysCall::Fork => proc::sys_fork(),
+            SysCall::Exit => proc::sys_exit(trapframe),
+            SysCall::Wait => proc::sys_wait(trapframe),
+            SysCall::Exec => proc::sys_exec(trapframe),
+            SysCall::Kill => proc::sys_kill(trapframe),
+            SysCall::GetPid => proc::sys_getpid(),
+            SysCall::Sbrk => proc::sys_sbrk(trapframe),
+            SysCall::Sleep => proc::sys_sleep(trapframe),
+            SysCall::Uptime => proc::sys_uptime(),
+            SysCall::Read => file::sys_read(trapframe),
+            SysCall::Write => file::sys_write(trapframe),
+            SysCall::Pipe => file::sys_pipe(trapframe),
+            SysCall::Dup => file::sys_dup(trapframe),
+            SysCall::Close => file::sys_close(trapframe),
+            SysCall::Fstat => file::sys_fstat(trapframe),
+            SysCall::Open => file::sys_open(trapframe),
+            SysCall::Chdir => file::sys_chdir(trapframe),
+            SysCall::Mknod => file::sys_mknod(trapframe),
+            SysCall::Unlink => file::sys_unlink(trapframe),
+            SysCall::Link => file::sys_link(trapframe),
+            SysCall::Mkdir => file::sys_mkdir(trapframe),
+            SysCall::Log => test_log(trapframe),
+        }
+    }
+
+    // Fetch the nth 64-bit system call argument.
+    fn nth_arg(trapframe: *mut TrapFrame, n: u8) -> SysResult {
+        let tf = unsafe { &*trapframe };
         match n {
-            0 => (*trapframe).a0,
-            1 => (*trapframe).a1,
-            2 => (*trapframe).a2,
-            3 => (*trapframe).a3,
-            4 => (*trapframe).a4,
-            5 => (*trapframe).a5,
-            _ => panic!("unsupported syscall argument"),
+            0 => Ok(tf.a0),
+            1 => Ok(tf.a1),
+            2 => Ok(tf.a2),
+            3 => Ok(tf.a3),
+            4 => Ok(tf.a4),
+            5 => Ok(tf.a5),
+            _ => Err(Errno::EINVAL),
         }
     }
 
-    fn from_trapframe(trapframe: *mut TrapFrame) -> SysCall {
-        match unsafe { (*trapframe).a7 } {
+    // Fetch the nth argument as a nul-terminated string into buf.
+    // Returns the string without its terminator.
+    fn arg_str(trapframe: *mut TrapFrame, n: u8, buf: &mut [u8]) -> Result<&[u8], Errno> {
+        let addr = Self::nth_arg(trapframe, n)?;
+        let pagetable = unsafe { PMASTER.my_proc().context.pagetable };
+        let len = PageTable::copyinstr(pagetable, buf, addr)?;
+        Ok(&buf[..len])
+    }
+
+    fn from_trapframe(trapframe: *mut TrapFrame) -> Option<SysCall> {
+        let syscall = match unsafe { (*trapframe).a7 } {
             1 => SysCall::Fork,
             2 => SysCall::Exit,
             3 => SysCall::Wait,
@@ -80,12 +138,14 @@ impl SysCall {
             20 => SysCall::Mkdir,
             21 => SysCall::Close,
             22 => SysCall::Log,
-            _ => panic!("unsupported syscall"),
-        }
+            _ => return None,
+        };
+        Some(syscall)
     }
 }
 
-fn test_log(trapframe: *mut TrapFrame) {
-    let a0 = unsafe { SysCall::nth_arg(trapframe, 0) };
+fn test_log(trapframe: *mut TrapFrame) -> SysResult {
+    let a0 = SysCall::nth_arg(trapframe, 0)?;
     println!("HELLO SYSCALL ARG  {}", a0);
+    Ok(0)
 }
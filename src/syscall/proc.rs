@@ -0,0 +1,112 @@
+use core::{mem::size_of, slice};
+
+use super::{Errno, SysCall, SysResult};
+use crate::arch::{MAXARG, MAXPATH, PGSIZE};
+use crate::exec::exec;
+use crate::fs::log::{begin_op, end_op};
+use crate::memory::{kalloc::KALLOC, vm::PageTable};
+use crate::process::{cpu::TrapFrame, master::PMASTER};
+use crate::trap::{ticks_chan, TICKS};
+
+pub(super) fn sys_fork() -> SysResult {
+    match unsafe { PMASTER.fork() } {
+        Some(pid) => Ok(pid as u64),
+        None => Err(Errno::EAGAIN),
+    }
+}
+
+pub(super) fn sys_exit(trapframe: *mut TrapFrame) -> SysResult {
+    let status = SysCall::nth_arg(trapframe, 0)? as i32;
+    unsafe { PMASTER.exit(status) }
+}
+
+pub(super) fn sys_wait(trapframe: *mut TrapFrame) -> SysResult {
+    let addr = SysCall::nth_arg(trapframe, 0)?;
+    let pid = unsafe { PMASTER.wait(addr) }?;
+    Ok(pid as u64)
+}
+
+pub(super) fn sys_kill(trapframe: *mut TrapFrame) -> SysResult {
+    let pid = SysCall::nth_arg(trapframe, 0)? as usize;
+    unsafe { PMASTER.kill(pid) }?;
+    Ok(0)
+}
+
+pub(super) fn sys_getpid() -> SysResult {
+    Ok(unsafe { PMASTER.my_proc().context.pid } as u64)
+}
+
+pub(super) fn sys_exec(trapframe: *mut TrapFrame) -> SysResult {
+    let uargv = SysCall::nth_arg(trapframe, 1)?;
+    let pagetable = unsafe { PMASTER.my_proc().context.pagetable };
+
+    let mut path = [0; MAXPATH];
+    let path = SysCall::arg_str(trapframe, 0, &mut path)?;
+
+    // each argument string is copied into a page of its own.
+    let mut pages = [0; MAXARG];
+    let mut argv: [&[u8]; MAXARG] = [&[]; MAXARG];
+    let mut argc = 0;
+    let ret = loop {
+        if argc == MAXARG {
+            break Err(Errno::E2BIG);
+        }
+        let mut uarg = [0; size_of::<u64>()];
+        if let Err(err) = PageTable::copyin(
+            pagetable,
+            &mut uarg,
+            uargv + (argc * size_of::<u64>()) as u64,
+        ) {
+            break Err(err.into());
+        }
+        let uarg = u64::from_ne_bytes(uarg);
+        if uarg == 0 {
+            begin_op();
+            let ret = exec(path, &argv[..argc]);
+            end_op();
+            break ret;
+        }
+        pages[argc] = match KALLOC.alloc() {
+            Some(page) => page,
+            None => break Err(Errno::ENOMEM),
+        };
+        let buf = unsafe { slice::from_raw_parts_mut(pages[argc] as *mut u8, PGSIZE as usize) };
+        argv[argc] = match PageTable::copyinstr(pagetable, buf, uarg) {
+            Ok(len) => &buf[..len],
+            Err(err) => break Err(err.into()),
+        };
+        argc += 1;
+    };
+
+    for &page in pages.iter().filter(|&&page| page != 0) {
+        KALLOC.free(page);
+    }
+    ret
+}
+
+// grow (or shrink) the heap by n bytes and return the old break.
+pub(super) fn sys_sbrk(trapframe: *mut TrapFrame) -> SysResult {
+    let n = SysCall::nth_arg(trapframe, 0)? as i64;
+    let addr = unsafe { PMASTER.my_proc().context.sz };
+    unsafe { PMASTER.growproc(n) }?;
+    Ok(addr)
+}
+
+pub(super) fn sys_sleep(trapframe: *mut TrapFrame) -> SysResult {
+    let n = SysCall::nth_arg(trapframe, 0)?;
+    let mut ticks = TICKS.lock();
+    let ticks0 = *ticks;
+    while *ticks - ticks0 < n {
+        if unsafe { PMASTER.killed() } {
+            return Err(Errno::EINTR);
+        }
+        ticks = unsafe { PMASTER.sleep(ticks_chan(), ticks) };
+    }
+    Ok(0)
+}
+
+// return how many clock tick interrupts have occurred
+// since start.
+pub(super) fn sys_uptime() -> SysResult {
+    Ok(*TICKS.lock())
+}
@@ -1,19 +1,36 @@
-use crate::arch::{cpu_id, intr_off, make_satp, w_sip, PGSIZE};
+use crate::arch::{
+    cpu_id, intr_off, intr_on, make_satp, r_sstatus, w_sip, w_sstatus, PGSIZE, ROOTDEV,
+};
+use crate::driver::{uart::Uart, virtio_disk};
+use crate::fs;
+use crate::fs::dir::namei;
 use crate::layout::TRAPTEXT;
-use crate::memory::layout::{KERNELVEC, TRAMPOLINE};
+use crate::lock::spinlock::SpinLock;
+use crate::memory::layout::{KERNELVEC, TRAMPOLINE, UART0_IRQ, VIRTIO0_IRQ};
+use crate::memory::vm::PageTable;
 use crate::process::cpu::CMASTER;
-use crate::{print, syscall, PMASTER};
+use crate::{print, println, syscall, PMASTER};
+use core::sync::atomic::{AtomicBool, Ordering};
 use riscv::register::scause::Exception;
 use riscv::register::{
     satp,
     scause::{self, Interrupt, Trap},
     sepc, sip,
     sstatus::{self, SPP},
+    stval,
     stvec::{self, TrapMode},
 };
 
 pub(crate) mod plic;
 
+// number of timer interrupts since boot.
+pub(crate) static TICKS: SpinLock<u64> = SpinLock::new(0);
+
+// the channel to sleep on while waiting for TICKS to advance.
+pub(crate) fn ticks_chan() -> usize {
+    &TICKS as *const SpinLock<u64> as usize
+}
+
 pub(crate) fn init() {
     unsafe {
         riscv::register::stvec::write(KERNELVEC as usize, TrapMode::Direct);
@@ -27,13 +44,14 @@ extern "C" {
 
 #[no_mangle]
 extern "C" fn kerneltrap() {
+    let sepc = sepc::read();
+    let sstatus = r_sstatus();
+    assert_eq!(sstatus::read().spp(), SPP::Supervisor);
     assert!(!sstatus::read().sie());
     match devintr() {
         // Software interrupt from a machine-mode timer interrupt.
         Interrupt::SupervisorSoft => {
-            // acknowledge the software interrupt by clearing
-            // the SSIP bit in sip.
-            w_sip(sip::read().bits() & !2);
+            timerintr();
             let pin = unsafe { CMASTER.my_cpu().pin };
             if pin.is_some() {
                 // give up the CPU.
@@ -43,11 +61,14 @@ extern "C" fn kerneltrap() {
             }
         }
         // Supervisor external interrupt
-        Interrupt::SupervisorExternal => {
-            unimplemented!("Interrupt::SupervisorExternal");
-        }
+        Interrupt::SupervisorExternal => externintr(),
         i => panic!("Kernel Panic: {:?} should not be handled in kernel", i),
     }
+
+    // the step() may have caused some traps to occur,
+    // so restore trap registers for use by kernelvec.S's sepc instruction.
+    sepc::write(sepc);
+    w_sstatus(sstatus);
 }
 
 #[no_mangle]
@@ -67,23 +88,105 @@ extern "C" fn usertrap() {
         // give up the CPU if this is a timer interrupt.
         Trap::Interrupt(Interrupt::SupervisorSoft) => unsafe {
             // timer interrupt
+            timerintr();
             PMASTER.step();
         },
 
+        // device interrupt via the PLIC.
+        Trap::Interrupt(Interrupt::SupervisorExternal) => externintr(),
+
         Trap::Exception(Exception::UserEnvCall) => {
             // userland system call
 
+            if unsafe { PMASTER.killed() } {
+                unsafe { PMASTER.exit(-1) };
+            }
+
             // an interrupt will change sepc, scause, and sstatus,
             // so enable only now that we're done with those registers.
-            intr_off();
+            intr_on();
             syscall::handle(trapframe);
         }
 
-        t => panic!("could not handle{:?}", t),
+        // a load or store to a page sbrk() reserved but nobody
+        // has touched yet, or a store to a copy-on-write page.
+        t @ Trap::Exception(Exception::LoadPageFault | Exception::StorePageFault) => {
+            let (pagetable, sz) = (p.context.pagetable, p.context.sz);
+            let va = stval::read() as u64;
+            let mut fixed = PageTable::uvmlazy(pagetable, va, sz);
+            if fixed.is_err() && t == Trap::Exception(Exception::StorePageFault) {
+                fixed = PageTable::uvmcow(pagetable, va);
+            }
+            if fixed.is_err() {
+                user_fault(p.context.pid, t);
+            }
+        }
+
+        t => user_fault(p.context.pid, t),
     }
+
+    if unsafe { PMASTER.killed() } {
+        unsafe { PMASTER.exit(-1) };
+    }
+
     usertrapret();
 }
 
+// a fault or other exception caused by the user program:
+// kill the process rather than the kernel.
+fn user_fault(pid: usize, t: Trap) {
+    println!(
+        "usertrap(): unexpected scause {:#x} ({:?}) pid={}",
+        scause::read().bits(),
+        t,
+        pid
+    );
+    println!(
+        "            sepc={:#x} stval={:#x}",
+        sepc::read(),
+        stval::read()
+    );
+    unsafe { PMASTER.set_killed() };
+}
+
+fn clockintr() {
+    let mut ticks = TICKS.lock();
+    *ticks += 1;
+    unsafe {
+        PMASTER.wakeup(ticks_chan());
+    }
+}
+
+// a timer interrupt arrives on every hart as a software interrupt,
+// but only hart 0 advances the tick count.
+fn timerintr() {
+    if cpu_id() == 0 {
+        clockintr();
+    }
+    // acknowledge the software interrupt by clearing
+    // the SSIP bit in sip.
+    w_sip(sip::read().bits() & !2);
+}
+
+// a device interrupt routed through the PLIC.
+fn externintr() {
+    // irq indicates which device interrupted.
+    let irq = plic::claim();
+    match irq as u64 {
+        0 => {} // another hart claimed it first.
+        UART0_IRQ => Uart::new().intr(),
+        VIRTIO0_IRQ => virtio_disk::intr(),
+        _ => println!("unexpected interrupt irq={}", irq),
+    }
+
+    // the PLIC allows each device to raise at most one
+    // interrupt at a time; tell the PLIC the device is
+    // now allowed to interrupt again.
+    if irq != 0 {
+        plic::complete(irq);
+    }
+}
+
 // check if it's an external interrupt or software interrupt,and handle it.
 fn devintr() -> Interrupt {
     let scause = scause::read().cause();
@@ -93,18 +196,30 @@ fn devintr() -> Interrupt {
     }
 }
 
+static FIRST: AtomicBool = AtomicBool::new(true);
+
 // A fork child's very first scheduling by scheduler()
 // will swtch to forkret.
 pub(crate) fn forkret() {
     let p = unsafe { PMASTER.my_proc() };
     unsafe { p.info.unlock() };
+
+    if FIRST.swap(false, Ordering::AcqRel) {
+        // File system initialization must be run in the context of a
+        // regular process (e.g., because it calls sleep), and thus cannot
+        // be run from kmain().
+        fs::fsinit(ROOTDEV);
+
+        // the first process starts in the root directory;
+        // namei sleeps too, so this cannot happen in user_init().
+        p.context.cwd = namei(b"/");
+    }
     usertrapret();
 }
 
 // return to user space
 pub(crate) fn usertrapret() {
     intr_off();
-    print!("{}", cpu_id());
     let p = unsafe { PMASTER.my_proc() };
     // send syscalls, interrupts, and exceptions to uservec in trampoline.
     let trapframe = p.context.trapframe;
@@ -1,4 +1,4 @@
-use core::ptr::write_volatile;
+use core::ptr::{read_volatile, write_volatile};
 
 use crate::{
     arch::cpu_id,
@@ -23,3 +23,19 @@ pub(crate) fn init_hart() {
         write_volatile(plic_spriority, 0);
     }
 }
+
+// ask the PLIC what interrupt we should serve.
+pub(crate) fn claim() -> u32 {
+    let id = cpu_id() as u64;
+    let plic_sclaim = (PLIC + 0x201004 + 0x2000 * id) as *const u32;
+    unsafe { read_volatile(plic_sclaim) }
+}
+
+// tell the PLIC we've served this IRQ.
+pub(crate) fn complete(irq: u32) {
+    let id = cpu_id() as u64;
+    let plic_sclaim = (PLIC + 0x201004 + 0x2000 * id) as *mut u32;
+    unsafe {
+        write_volatile(plic_sclaim, irq);
+    }
+}